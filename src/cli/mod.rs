@@ -0,0 +1,422 @@
+use crate::stackalc::bytecode::{DecodeError, decode};
+use crate::stackalc::parser::ParseError;
+use crate::stackalc::program_file::{self, ProgramFileError};
+use crate::stackalc::registers::MAX_REGISTERS;
+use crate::stackalc::runtime::{RuntimeError, STEP_LIMIT};
+use crate::stackalc::snapshot::{Snapshot, SnapshotError};
+use crate::stackalc::{CalcMode, Stackalc, UnknownMode};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+mod output;
+mod repl;
+
+const USAGE: &str = "\
+Usage:
+  stackalc                                          Start the interactive TUI
+  stackalc eval [OPTIONS] EXPRESSION   Evaluate an expression (INFIX by default)
+  stackalc run [OPTIONS] FILE          Run a program file
+  stackalc resume [OPTIONS] SNAPSHOT   Carry on running a snapshot from where it was paused
+  stackalc --repl [--mode MODE]        Read lines from stdin, keeping the stack between them
+
+Options:
+  --mode infix|postfix|raw    Input mode, overriding the file's header or extension
+  --regs                      Also print the registers that hold a value
+  --registers N               Start with N registers instead of 32, unless the program sets its own
+  --format text|json|jsonl    Print the program, a trace of every step and the final state as JSON
+                              or as JSON Lines instead of the final stack
+  --seed N                    Seed the random number generator, so rng gives the same values every run
+  --steps N                   Pause after N steps instead of running to the end
+  --snapshot PATH             Save the machine to PATH where the run stops, even if it failed";
+
+/// Runs a command line without ever touching the terminal. The final stack is printed bottom to
+/// top, one value per line.
+pub fn run(args: &[String]) -> ExitCode {
+    let result = match args.split_first() {
+        Some((command, _)) if matches!(command.as_str(), "help" | "--help" | "-h") => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Some((command, rest)) if command == "eval" => Options::parse(rest).and_then(eval),
+        Some((command, rest)) if command == "run" => Options::parse(rest).and_then(run_file),
+        Some((command, rest)) if command == "resume" => Options::parse(rest).and_then(resume),
+        Some((command, _)) if command.starts_with("--") => {
+            Options::parse(args).and_then(|options| {
+                if options.repl {
+                    repl::run(options)
+                } else {
+                    Err(CliError::Usage(String::from("Missing command")))
+                }
+            })
+        }
+        Some((command, _)) => Err(CliError::Usage(format!("Unknown command '{}'", command))),
+        None => Err(CliError::Usage(String::from("Missing command"))),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(CliError::Usage(message)) => {
+            eprintln!("error: {}\n\n{}", message, USAGE);
+            ExitCode::from(2)
+        }
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Text,
+    Json,
+    Jsonl,
+}
+
+struct Options {
+    mode: Option<CalcMode>,
+    registers: bool,
+    register_count: Option<usize>,
+    format: Format,
+    repl: bool,
+    seed: Option<u64>,
+    steps: Option<usize>,
+    snapshot: Option<PathBuf>,
+    arguments: Vec<String>,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Options, CliError> {
+        let mut options = Options {
+            mode: None,
+            registers: false,
+            register_count: None,
+            format: Format::Text,
+            repl: false,
+            seed: None,
+            steps: None,
+            snapshot: None,
+            arguments: Vec::new(),
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--mode" => {
+                    let name = args
+                        .next()
+                        .ok_or_else(|| CliError::Usage(String::from("--mode needs a value")))?;
+                    options.mode = Some(
+                        name.parse()
+                            .map_err(|err: UnknownMode| CliError::Usage(err.to_string()))?,
+                    );
+                }
+                "--regs" => options.registers = true,
+                "--registers" => {
+                    let count = args.next().ok_or_else(|| {
+                        CliError::Usage(String::from("--registers needs a value"))
+                    })?;
+                    let count = count.parse().map_err(|_| {
+                        CliError::Usage(format!("Invalid number of registers '{}'", count))
+                    })?;
+                    if count > MAX_REGISTERS {
+                        return Err(CliError::TooManyRegisters(count));
+                    }
+                    options.register_count = Some(count);
+                }
+                "--format" => {
+                    let name = args
+                        .next()
+                        .ok_or_else(|| CliError::Usage(String::from("--format needs a value")))?;
+                    options.format = parse_format(name)?;
+                }
+                "--repl" => options.repl = true,
+                "--seed" => {
+                    let seed = args
+                        .next()
+                        .ok_or_else(|| CliError::Usage(String::from("--seed needs a value")))?;
+                    options.seed = Some(
+                        seed.parse()
+                            .map_err(|_| CliError::Usage(format!("Invalid seed '{}'", seed)))?,
+                    );
+                }
+                "--steps" => {
+                    let steps = args
+                        .next()
+                        .ok_or_else(|| CliError::Usage(String::from("--steps needs a value")))?;
+                    options.steps = Some(steps.parse().map_err(|_| {
+                        CliError::Usage(format!("Invalid number of steps '{}'", steps))
+                    })?);
+                }
+                "--snapshot" => {
+                    let path = args
+                        .next()
+                        .ok_or_else(|| CliError::Usage(String::from("--snapshot needs a path")))?;
+                    options.snapshot = Some(PathBuf::from(path));
+                }
+                _ => options.arguments.push(arg.clone()),
+            }
+        }
+
+        Ok(options)
+    }
+
+    /// A fresh VM with the register file asked for on the command line. Programs, bytecode and
+    /// snapshots that declare their own size replace it when they are loaded.
+    fn machine(&self) -> Stackalc {
+        let mut stackalc = Stackalc::default();
+        if let Some(count) = self.register_count {
+            stackalc.set_register_count(count);
+        }
+        stackalc
+    }
+}
+
+fn parse_format(name: &str) -> Result<Format, CliError> {
+    match name {
+        "text" => Ok(Format::Text),
+        "json" => Ok(Format::Json),
+        "jsonl" => Ok(Format::Jsonl),
+        _ => Err(CliError::Usage(format!(
+            "Unknown format '{}', expected text, json or jsonl",
+            name
+        ))),
+    }
+}
+
+fn eval(options: Options) -> Result<(), CliError> {
+    if options.arguments.is_empty() {
+        return Err(CliError::Usage(String::from("Missing expression")));
+    }
+
+    let mut stackalc = options.machine();
+    let loaded = stackalc
+        .parse(
+            options.mode.unwrap_or(CalcMode::INFIX),
+            &options.arguments.join(" "),
+        )
+        .map(|()| (stackalc, 0))
+        .map_err(CliError::Parse);
+    execute(loaded, &options)
+}
+
+fn run_file(options: Options) -> Result<(), CliError> {
+    let [path] = options.arguments.as_slice() else {
+        return Err(CliError::Usage(String::from(
+            "Expected exactly one program file",
+        )));
+    };
+    let loaded = load_file(Path::new(path), &options).map(|stackalc| (stackalc, 0));
+    execute(loaded, &options)
+}
+
+fn resume(options: Options) -> Result<(), CliError> {
+    let [path] = options.arguments.as_slice() else {
+        return Err(CliError::Usage(String::from(
+            "Expected exactly one snapshot",
+        )));
+    };
+    // The snapshot carries on the random sequence where it stopped, which a new seed would reset.
+    if options.seed.is_some() {
+        return Err(CliError::Usage(String::from(
+            "--seed cannot be used with resume",
+        )));
+    }
+    let path = Path::new(path);
+
+    let loaded = fs::read_to_string(path)
+        .map_err(|err| CliError::Io(path.to_path_buf(), err))
+        .and_then(|contents| Snapshot::parse(&contents).map_err(CliError::Snapshot))
+        .and_then(|snapshot| {
+            let pc = snapshot.pc;
+            let mut stackalc = Stackalc::default();
+            snapshot
+                .restore(&mut stackalc)
+                .map_err(CliError::Snapshot)?;
+            Ok((stackalc, pc))
+        });
+    execute(loaded, &options)
+}
+
+fn load_file(path: &Path, options: &Options) -> Result<Stackalc, CliError> {
+    let mut stackalc = options.machine();
+
+    if path
+        .extension()
+        .is_some_and(|extension| extension == "skbc")
+    {
+        let bytes = fs::read(path).map_err(|err| CliError::Io(path.to_path_buf(), err))?;
+        let bytecode = decode(&bytes).map_err(CliError::Decode)?;
+        if bytecode.registers > MAX_REGISTERS {
+            return Err(CliError::TooManyRegisters(bytecode.registers));
+        }
+        stackalc.replace_program(bytecode.expr);
+        stackalc.set_register_count(bytecode.registers);
+    } else {
+        let contents =
+            fs::read_to_string(path).map_err(|err| CliError::Io(path.to_path_buf(), err))?;
+        let program = program_file::parse(path, &contents).map_err(CliError::ProgramFile)?;
+        let mode = options.mode.or(program.mode).unwrap_or(CalcMode::RAW);
+        stackalc
+            .parse(mode, &program.source)
+            .map_err(CliError::Parse)?;
+    }
+
+    Ok(stackalc)
+}
+
+/// Runs a loaded program from the given offset and prints the outcome in the requested format.
+fn execute(loaded: Result<(Stackalc, usize), CliError>, options: &Options) -> Result<(), CliError> {
+    let (stackalc, result) = match loaded {
+        Ok((mut stackalc, pc)) => {
+            if let Some(seed) = options.seed {
+                stackalc.set_seed(seed);
+            }
+            if options.format != Format::Text {
+                stackalc.trace = Some(Vec::new());
+            }
+            let result = run_program(&mut stackalc, pc, options);
+            (Some(stackalc), result)
+        }
+        Err(err) => (None, Err(err)),
+    };
+
+    match options.format {
+        Format::Text => {
+            if let (Some(stackalc), Ok(())) = (&stackalc, &result) {
+                print_stack(stackalc);
+                if options.registers {
+                    print_registers(stackalc);
+                }
+            }
+        }
+        // Machine-readable output reports errors in-band too, so a consumer always gets a document.
+        format => output::print(format, stackalc.as_ref(), result.as_ref().err()),
+    }
+    result
+}
+
+/// Runs until the program ends, fails or has taken `--steps` steps, then saves the machine as it
+/// stands to `--snapshot`. Pausing is not an error.
+fn run_program(stackalc: &mut Stackalc, pc: usize, options: &Options) -> Result<(), CliError> {
+    let result = stackalc.run_from(pc, options.steps.unwrap_or(STEP_LIMIT));
+
+    if let Some(path) = &options.snapshot {
+        let stopped_at = match &result {
+            Ok(()) => stackalc.expr.len(),
+            Err(err) => err.offset(),
+        };
+        fs::write(path, Snapshot::capture(stackalc, stopped_at).render())
+            .map_err(|err| CliError::Write(path.clone(), err))?;
+    }
+
+    match result {
+        Err(RuntimeError::StepLimit { offset, limit }) if options.steps.is_some() => {
+            eprintln!("Paused at {:#06X} after {} steps", offset, limit);
+            Ok(())
+        }
+        result => result.map_err(CliError::Runtime),
+    }
+}
+
+fn print_stack(stackalc: &Stackalc) {
+    for value in &stackalc.stack {
+        println!("{}", value);
+    }
+}
+
+fn print_registers(stackalc: &Stackalc) {
+    for (register, value) in stackalc.memory.iter().enumerate() {
+        if let Some(value) = value {
+            println!("{} = {}", stackalc.register_label(register), value);
+        }
+    }
+}
+
+#[derive(Debug)]
+enum CliError {
+    Usage(String),
+    Io(PathBuf, io::Error),
+    Write(PathBuf, io::Error),
+    Snapshot(SnapshotError),
+    ProgramFile(ProgramFileError),
+    Decode(DecodeError),
+    TooManyRegisters(usize),
+    Parse(ParseError),
+    Runtime(RuntimeError),
+    ReplErrors(usize),
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::Usage(message) => write!(f, "{}", message),
+            CliError::Io(path, err) => write!(f, "Could not read {}: {}", path.display(), err),
+            CliError::Write(path, err) => write!(f, "Could not write {}: {}", path.display(), err),
+            CliError::Snapshot(err) => write!(f, "{}", err),
+            CliError::ProgramFile(err) => write!(f, "{}", err),
+            CliError::Decode(err) => write!(f, "{}", err),
+            CliError::TooManyRegisters(count) => write!(
+                f,
+                "{} registers requested, at most {} are supported",
+                count, MAX_REGISTERS
+            ),
+            CliError::Parse(err) => write!(f, "{}", err),
+            CliError::Runtime(err) => write!(f, "{}", err),
+            CliError::ReplErrors(errors) => write!(f, "{} lines failed", errors),
+        }
+    }
+}
+
+impl std::error::Error for CliError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(args: &[&str]) -> Result<Options, CliError> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        Options::parse(&args)
+    }
+
+    #[test]
+    fn flags_are_taken_out_of_the_arguments() {
+        let options = options(&["--mode", "postfix", "3", "--seed", "7", "4", "+"]).unwrap();
+
+        assert!(matches!(options.mode, Some(CalcMode::POSTFIX)));
+        assert_eq!(options.seed, Some(7));
+        assert_eq!(options.arguments, ["3", "4", "+"]);
+    }
+
+    #[test]
+    fn bad_flags_are_rejected() {
+        assert!(matches!(
+            options(&["--mode", "prefix"]),
+            Err(CliError::Usage(_))
+        ));
+        assert!(matches!(
+            options(&["--format", "xml"]),
+            Err(CliError::Usage(_))
+        ));
+        assert!(matches!(options(&["--steps"]), Err(CliError::Usage(_))));
+        assert!(matches!(
+            options(&["--registers", "5000"]),
+            Err(CliError::TooManyRegisters(5000))
+        ));
+    }
+
+    #[test]
+    fn running_out_of_steps_pauses_instead_of_failing() {
+        let mut stackalc = Stackalc::default();
+        stackalc.parse(CalcMode::RAW, "ldc:1 ldc:2 add").unwrap();
+
+        run_program(&mut stackalc, 0, &options(&["--steps", "2"]).unwrap()).unwrap();
+        assert_eq!(stackalc.stack, [1.0, 2.0]);
+
+        run_program(&mut stackalc, 2, &options(&[]).unwrap()).unwrap();
+        assert_eq!(stackalc.stack, [3.0]);
+    }
+}
@@ -0,0 +1,103 @@
+use crate::cli::{CliError, Format};
+use crate::stackalc::Stackalc;
+use crate::stackalc::json::Json;
+
+/// Prints a run as one JSON document, or as JSON Lines: a `program` record, one `step` per
+/// executed instruction, the final `state`, and an `error` if the run failed. Without a
+/// `stackalc` the program never loaded, so only the error is printed.
+pub fn print(format: Format, stackalc: Option<&Stackalc>, error: Option<&CliError>) {
+    let error = error.map(|error| Json::String(error.to_string()));
+
+    match format {
+        Format::Json => {
+            let mut fields = Vec::new();
+            if let Some(stackalc) = stackalc {
+                fields.extend([
+                    (String::from("seed"), seed(stackalc)),
+                    (String::from("program"), program(stackalc)),
+                    (String::from("trace"), Json::Array(steps(stackalc))),
+                    (String::from("stack"), Json::numbers(&stackalc.stack)),
+                    (String::from("registers"), registers(stackalc)),
+                ]);
+            }
+            if let Some(error) = error {
+                fields.push((String::from("error"), error));
+            }
+            println!("{}", Json::Object(fields));
+        }
+        Format::Jsonl => {
+            if let Some(stackalc) = stackalc {
+                println!(
+                    "{}",
+                    Json::object([
+                        ("type", Json::String(String::from("program"))),
+                        ("seed", seed(stackalc)),
+                        ("instructions", program(stackalc)),
+                    ])
+                );
+                for step in steps(stackalc) {
+                    let Json::Object(fields) = step else {
+                        continue;
+                    };
+                    let mut record =
+                        vec![(String::from("type"), Json::String(String::from("step")))];
+                    record.extend(fields);
+                    println!("{}", Json::Object(record));
+                }
+                println!(
+                    "{}",
+                    Json::object([
+                        ("type", Json::String(String::from("state"))),
+                        ("stack", Json::numbers(&stackalc.stack)),
+                        ("registers", registers(stackalc)),
+                    ])
+                );
+            }
+            if let Some(error) = error {
+                println!(
+                    "{}",
+                    Json::object([
+                        ("type", Json::String(String::from("error"))),
+                        ("message", error),
+                    ])
+                );
+            }
+        }
+        Format::Text => {}
+    }
+}
+
+/// A string, since seeds above 2^53 do not survive being read back as a JSON number.
+fn seed(stackalc: &Stackalc) -> Json {
+    Json::String(stackalc.seed.to_string())
+}
+
+fn program(stackalc: &Stackalc) -> Json {
+    Json::Array(
+        stackalc
+            .expr
+            .iter()
+            .map(|instruction| Json::String(instruction.to_string()))
+            .collect(),
+    )
+}
+
+fn steps(stackalc: &Stackalc) -> Vec<Json> {
+    stackalc
+        .trace
+        .iter()
+        .flatten()
+        .enumerate()
+        .map(|(step, entry)| entry.to_json(step))
+        .collect()
+}
+
+fn registers(stackalc: &Stackalc) -> Json {
+    Json::registers(
+        stackalc
+            .memory
+            .iter()
+            .enumerate()
+            .filter_map(|(register, value)| Some((register, value.as_ref()?))),
+    )
+}
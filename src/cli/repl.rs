@@ -0,0 +1,159 @@
+use crate::cli::{CliError, Options, print_registers, print_stack};
+use crate::stackalc::runtime::STEP_LIMIT;
+use crate::stackalc::{CalcMode, Stackalc};
+use std::io::{self, BufRead, IsTerminal, Write};
+
+const HELP: &str = "\
+Each line is run as a program of its own against the current stack and registers.
+  :mode [infix|postfix|raw]   Show or change the input mode
+  :stack                      Print the stack, bottom to top
+  :regs                       Print the registers that hold a value
+  :clear                      Empty the stack and the registers
+  :quit                       Leave the REPL";
+
+/// Reads lines from stdin until it ends or `:quit`. A prompt is only shown on a terminal, so
+/// piped input produces nothing but results and errors. Fails if any line failed.
+pub fn run(options: Options) -> Result<(), CliError> {
+    let mut repl = Repl {
+        stackalc: options.machine(),
+        mode: options.mode.unwrap_or(CalcMode::INFIX),
+        errors: 0,
+    };
+    if let Some(seed) = options.seed {
+        repl.stackalc.set_seed(seed);
+    }
+    let interactive = io::stdin().is_terminal();
+
+    let mut lines = io::stdin().lock().lines();
+    loop {
+        if interactive {
+            print!("{}> ", repl.mode.name());
+            let _ = io::stdout().flush();
+        }
+        let Some(Ok(line)) = lines.next() else {
+            break;
+        };
+
+        match line.trim() {
+            "" => {}
+            ":quit" | ":q" => break,
+            line if line.starts_with(':') => repl.command(line),
+            line => repl.evaluate(line),
+        }
+    }
+
+    match repl.errors {
+        0 => Ok(()),
+        errors => Err(CliError::ReplErrors(errors)),
+    }
+}
+
+struct Repl {
+    stackalc: Stackalc,
+    mode: CalcMode,
+    errors: usize,
+}
+
+impl Repl {
+    fn evaluate(&mut self, line: &str) {
+        // A failing line leaves the stack and registers as they were before it.
+        let previous = self.stackalc.clone();
+        self.stackalc.expr.clear();
+        self.stackalc.spans.clear();
+
+        let result = self
+            .stackalc
+            .parse_continuation(self.mode, line)
+            .map_err(CliError::Parse)
+            .and_then(|()| {
+                self.stackalc
+                    .run_to_completion(STEP_LIMIT)
+                    .map_err(CliError::Runtime)
+            });
+
+        match result {
+            Ok(()) => {
+                if let Some(top) = self.stackalc.stack.last() {
+                    println!("{}", top);
+                }
+            }
+            Err(err) => {
+                self.stackalc = previous;
+                self.fail(err);
+            }
+        }
+    }
+
+    fn command(&mut self, line: &str) {
+        let args: Vec<&str> = line.split_whitespace().collect();
+
+        match args.as_slice() {
+            [":mode"] => println!("{}", self.mode.name()),
+            [":mode", name] => match name.parse::<CalcMode>() {
+                Ok(mode) => self.mode = mode,
+                Err(err) => self.fail(CliError::Usage(err.to_string())),
+            },
+            [":stack"] => print_stack(&self.stackalc),
+            [":regs"] => print_registers(&self.stackalc),
+            [":clear"] => self.stackalc.clear(),
+            [":help"] => println!("{}", HELP),
+            _ => self.fail(CliError::Usage(format!("Unknown command '{}'", line))),
+        }
+    }
+
+    fn fail(&mut self, err: CliError) {
+        eprintln!("error: {}", err);
+        self.errors += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repl(mode: CalcMode) -> Repl {
+        Repl {
+            stackalc: Stackalc::default(),
+            mode,
+            errors: 0,
+        }
+    }
+
+    #[test]
+    fn postfix_lines_share_the_stack() {
+        let mut repl = repl(CalcMode::POSTFIX);
+
+        repl.evaluate("3 4");
+        repl.evaluate("+");
+
+        assert_eq!(repl.errors, 0);
+        assert_eq!(repl.stackalc.stack, vec![7.0]);
+
+        repl.evaluate("*");
+        assert_eq!(repl.errors, 1);
+        assert_eq!(repl.stackalc.stack, vec![7.0]);
+    }
+
+    #[test]
+    fn registers_persist_and_failed_lines_change_nothing() {
+        let mut repl = repl(CalcMode::INFIX);
+
+        repl.command(":mode raw");
+        repl.evaluate("ldc:5 stv:0");
+        repl.evaluate("ldv:0 ldv:0 mul");
+        assert_eq!(repl.stackalc.stack, [25.0]);
+
+        repl.evaluate("ldc:1 stv:0 ldv:1");
+        assert_eq!(repl.errors, 1);
+        assert_eq!(repl.stackalc.memory[0], Some(5.0));
+        assert_eq!(repl.stackalc.stack, [25.0]);
+
+        repl.command(":mode prefix");
+        assert_eq!(repl.errors, 2);
+        assert!(matches!(repl.mode, CalcMode::RAW));
+
+        repl.command(":clear");
+        assert!(repl.stackalc.stack.is_empty());
+        assert_eq!(repl.stackalc.memory[0], None);
+    }
+}
@@ -1,15 +1,46 @@
-use std::io;
 use crate::stackalc::Stackalc;
+use crate::stackalc::session;
+use std::env;
+use std::process::ExitCode;
 
+mod cli;
 mod stackalc;
 mod tui;
 
-fn main() -> io::Result<()> {
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        return cli::run(&args);
+    }
+
     let terminal = ratatui::init();
-    let stackalc = Stackalc::default();
+    // Stepping in the TUI always records a trace, for the Trace panel and `:trace export`.
+    let mut stackalc = Stackalc {
+        trace: Some(Vec::new()),
+        ..Stackalc::default()
+    };
+    stackalc.offer_session();
     let result = stackalc.run(terminal);
-    
+
     ratatui::restore();
-    
-    result
+
+    // Quitting before answering the restore prompt keeps the offered session for next time.
+    if stackalc.pending_session.is_none()
+        && let Some(path) = session::default_path()
+        && let Err(err) = stackalc.write_session(&path)
+    {
+        eprintln!(
+            "warning: could not save the session to {}: {}",
+            path.display(),
+            err
+        );
+    }
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -0,0 +1,184 @@
+use crate::stackalc::instructions::Instruction;
+use std::fmt;
+
+/// Expression tree shared by the decompiler and the code generators.
+///
+/// `Binary(op, lhs, rhs)` reads in source order: `lhs` is pushed first and sits below `rhs` when
+/// `op` executes, so `ldc:5 ldc:3 sub` is `5 - 3`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Ast {
+    Num(f64),
+    Register(usize),
+    Rng,
+    Normal,
+    Exponential,
+    /// `rngi`, with its bounds in push order like `Binary`.
+    RandomInt(Box<Ast>, Box<Ast>),
+    /// `choose:n`, with the candidates in the order they were pushed.
+    Choose(Vec<Ast>),
+    Neg(Box<Ast>),
+    Binary(BinaryOp, Box<Ast>, Box<Ast>),
+    Conditional(Box<Ast>, Box<Ast>, Box<Ast>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Ceq,
+    Cgt,
+    Clt,
+}
+
+impl BinaryOp {
+    pub fn from_instruction(instruction: &Instruction) -> Option<Self> {
+        match instruction {
+            Instruction::ADD => Some(BinaryOp::Add),
+            Instruction::SUB => Some(BinaryOp::Sub),
+            Instruction::MUL => Some(BinaryOp::Mul),
+            Instruction::DIV => Some(BinaryOp::Div),
+            Instruction::CEQ => Some(BinaryOp::Ceq),
+            Instruction::CGT => Some(BinaryOp::Cgt),
+            Instruction::CLT => Some(BinaryOp::Clt),
+            _ => None,
+        }
+    }
+
+    /// Evaluates the operator the way the VM does, with `rhs` taken from the top of the stack.
+    pub fn apply(self, lhs: f64, rhs: f64) -> f64 {
+        let truth = |condition: bool| if condition { 1.0 } else { 0.0 };
+        match self {
+            BinaryOp::Add => lhs + rhs,
+            BinaryOp::Sub => lhs - rhs,
+            BinaryOp::Mul => lhs * rhs,
+            BinaryOp::Div => lhs / rhs,
+            BinaryOp::Ceq => truth(lhs == rhs),
+            BinaryOp::Cgt => truth(lhs >= rhs),
+            BinaryOp::Clt => truth(lhs <= rhs),
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Ceq => "==",
+            BinaryOp::Cgt => ">=",
+            BinaryOp::Clt => "<=",
+        }
+    }
+
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Ceq | BinaryOp::Cgt | BinaryOp::Clt => 1,
+            BinaryOp::Add | BinaryOp::Sub => 2,
+            BinaryOp::Mul | BinaryOp::Div => 3,
+        }
+    }
+}
+
+const CONDITIONAL_PRECEDENCE: u8 = 0;
+const UNARY_PRECEDENCE: u8 = 4;
+const ATOM_PRECEDENCE: u8 = 5;
+
+impl Ast {
+    fn precedence(&self) -> u8 {
+        match self {
+            Ast::Num(n) if n.is_sign_negative() => UNARY_PRECEDENCE,
+            Ast::Num(_)
+            | Ast::Register(_)
+            | Ast::Rng
+            | Ast::Normal
+            | Ast::Exponential
+            | Ast::RandomInt(..)
+            | Ast::Choose(_) => ATOM_PRECEDENCE,
+            Ast::Neg(_) => UNARY_PRECEDENCE,
+            Ast::Binary(op, _, _) => op.precedence(),
+            Ast::Conditional(..) => CONDITIONAL_PRECEDENCE,
+        }
+    }
+
+    /// Renders the tree as postfix tokens in the order the instructions push them.
+    pub fn postfix(&self) -> String {
+        match self {
+            Ast::Num(n) => n.to_string(),
+            Ast::Register(n) => format!("r{}", n),
+            Ast::Rng => String::from("rng"),
+            Ast::Normal => String::from("rngn"),
+            Ast::Exponential => String::from("rnge"),
+            Ast::RandomInt(lhs, rhs) => format!("{} {} rngi", lhs.postfix(), rhs.postfix()),
+            Ast::Choose(candidates) => {
+                let mut tokens: Vec<String> = candidates.iter().map(Ast::postfix).collect();
+                tokens.push(format!("choose:{}", candidates.len()));
+                tokens.join(" ")
+            }
+            Ast::Neg(operand) => format!("{} neg", operand.postfix()),
+            Ast::Binary(op, lhs, rhs) => {
+                format!("{} {} {}", lhs.postfix(), rhs.postfix(), op.symbol())
+            }
+            Ast::Conditional(condition, then, otherwise) => format!(
+                "{} {} {} ?:",
+                condition.postfix(),
+                then.postfix(),
+                otherwise.postfix()
+            ),
+        }
+    }
+
+    fn write_operand(&self, f: &mut fmt::Formatter<'_>, parenthesize: bool) -> fmt::Result {
+        if parenthesize {
+            write!(f, "({})", self)
+        } else {
+            write!(f, "{}", self)
+        }
+    }
+}
+
+impl fmt::Display for Ast {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ast::Num(n) => write!(f, "{}", n),
+            Ast::Register(n) => write!(f, "r{}", n),
+            Ast::Rng => write!(f, "rng()"),
+            Ast::Normal => write!(f, "rngn()"),
+            Ast::Exponential => write!(f, "rnge()"),
+            Ast::RandomInt(lhs, rhs) => write!(f, "rngi({}, {})", lhs, rhs),
+            Ast::Choose(candidates) => {
+                let candidates: Vec<String> = candidates.iter().map(Ast::to_string).collect();
+                write!(f, "choose({})", candidates.join(", "))
+            }
+            Ast::Neg(operand) => {
+                write!(f, "-")?;
+                operand.write_operand(f, operand.precedence() <= UNARY_PRECEDENCE)
+            }
+            Ast::Binary(op, lhs, rhs) => {
+                let precedence = op.precedence();
+                let is_comparison = precedence == BinaryOp::Ceq.precedence();
+                let lhs_parens = lhs.precedence() < precedence
+                    || (is_comparison && lhs.precedence() == precedence);
+                let rhs_associates = matches!(
+                    (op, rhs.as_ref()),
+                    (BinaryOp::Add, Ast::Binary(BinaryOp::Add, _, _))
+                        | (BinaryOp::Mul, Ast::Binary(BinaryOp::Mul, _, _))
+                );
+                let rhs_parens = rhs.precedence() < precedence
+                    || (rhs.precedence() == precedence && !rhs_associates)
+                    || (rhs.precedence() == UNARY_PRECEDENCE);
+
+                lhs.write_operand(f, lhs_parens)?;
+                write!(f, " {} ", op.symbol())?;
+                rhs.write_operand(f, rhs_parens)
+            }
+            Ast::Conditional(condition, then, otherwise) => {
+                condition.write_operand(f, condition.precedence() == CONDITIONAL_PRECEDENCE)?;
+                write!(f, " ? ")?;
+                then.write_operand(f, then.precedence() == CONDITIONAL_PRECEDENCE)?;
+                write!(f, " : {}", otherwise)
+            }
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use crate::stackalc::instructions::Instruction;
+use std::fmt;
+use std::ops::Range;
+
+const MAGIC: &[u8; 4] = b"SKBC";
+const VERSION: u8 = 1;
+
+/// Magic, version byte, register count and instruction count.
+const HEADER_LEN: usize = 4 + 1 + 4 + 4;
+
+/// A decoded program along with the number of registers it was written for.
+#[derive(Clone)]
+pub struct Bytecode {
+    pub registers: usize,
+    pub expr: Vec<Instruction>,
+}
+
+/// Encodes a program as `SKBC`, a version byte, the register count and instruction count as
+/// little-endian `u32`s, then one opcode byte per instruction followed by its operand: an `f64`
+/// for `ldc`, a `u32` for registers, branch targets and the counts of `shuffle` and `choose`.
+/// Programs that [`decode`] would reject are refused.
+pub fn encode(expr: &[Instruction], registers: usize) -> Result<Vec<u8>, EncodeError> {
+    let mut bytes = Vec::with_capacity(HEADER_LEN + 5 * expr.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.push(VERSION);
+    bytes.extend_from_slice(&to_u32(registers)?.to_le_bytes());
+    bytes.extend_from_slice(&to_u32(expr.len())?.to_le_bytes());
+
+    for (offset, instruction) in expr.iter().enumerate() {
+        if let Instruction::LDV(register) | Instruction::STV(register) = instruction
+            && *register >= registers
+        {
+            return Err(EncodeError::RegisterOutOfRange {
+                offset,
+                register: *register,
+            });
+        }
+        bytes.push(opcode(instruction));
+        match instruction {
+            Instruction::LDC(value) => bytes.extend_from_slice(&value.to_le_bytes()),
+            Instruction::LDV(operand)
+            | Instruction::STV(operand)
+            | Instruction::BR(operand)
+            | Instruction::BRTRUE(operand)
+            | Instruction::BRFALSE(operand)
+            | Instruction::SHUFFLE(operand)
+            | Instruction::CHOOSE(operand) => {
+                bytes.extend_from_slice(&to_u32(*operand)?.to_le_bytes())
+            }
+            _ => {}
+        }
+    }
+
+    Ok(bytes)
+}
+
+fn to_u32(value: usize) -> Result<u32, EncodeError> {
+    u32::try_from(value).map_err(|_| EncodeError::TooLarge(value))
+}
+
+/// Byte range of every instruction in the output of [`encode`].
+pub fn instruction_ranges(expr: &[Instruction]) -> Vec<Range<usize>> {
+    let mut start = HEADER_LEN;
+    expr.iter()
+        .map(|instruction| {
+            let end = start + 1 + operand_len(instruction);
+            let range = start..end;
+            start = end;
+            range
+        })
+        .collect()
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Bytecode, DecodeError> {
+    let mut reader = Reader { bytes, position: 0 };
+
+    if reader.take(4)? != MAGIC {
+        return Err(DecodeError::BadMagic);
+    }
+    let version = reader.take(1)?[0];
+    if version != VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    let registers = reader.u32()? as usize;
+    let count = reader.u32()? as usize;
+
+    let mut expr = Vec::with_capacity(count.min(bytes.len()));
+    for _ in 0..count {
+        let position = reader.position;
+        let instruction = match reader.take(1)?[0] {
+            0x01 => Instruction::LDC(reader.f64()?),
+            0x02 => Instruction::LDV(reader.u32()? as usize),
+            0x03 => Instruction::STV(reader.u32()? as usize),
+            0x04 => Instruction::NEG,
+            0x05 => Instruction::ADD,
+            0x06 => Instruction::MUL,
+            0x07 => Instruction::SUB,
+            0x08 => Instruction::DIV,
+            0x09 => Instruction::CEQ,
+            0x0A => Instruction::CGT,
+            0x0B => Instruction::CLT,
+            0x0C => Instruction::DUP,
+            0x0D => Instruction::POP,
+            0x0E => Instruction::NOP,
+            0x0F => Instruction::RNG,
+            0x10 => Instruction::BR(reader.u32()? as usize),
+            0x11 => Instruction::BRTRUE(reader.u32()? as usize),
+            0x12 => Instruction::BRFALSE(reader.u32()? as usize),
+            0x13 => Instruction::RNGI,
+            0x14 => Instruction::RNGN,
+            0x15 => Instruction::RNGE,
+            0x16 => Instruction::SHUFFLE(reader.u32()? as usize),
+            0x17 => Instruction::CHOOSE(reader.u32()? as usize),
+            opcode => return Err(DecodeError::UnknownOpcode { position, opcode }),
+        };
+        if let Instruction::LDV(register) | Instruction::STV(register) = instruction
+            && register >= registers
+        {
+            return Err(DecodeError::RegisterOutOfRange { position, register });
+        }
+        expr.push(instruction);
+    }
+
+    if reader.position != bytes.len() {
+        return Err(DecodeError::TrailingBytes(reader.position));
+    }
+
+    Ok(Bytecode { registers, expr })
+}
+
+fn opcode(instruction: &Instruction) -> u8 {
+    match instruction {
+        Instruction::LDC(_) => 0x01,
+        Instruction::LDV(_) => 0x02,
+        Instruction::STV(_) => 0x03,
+        Instruction::NEG => 0x04,
+        Instruction::ADD => 0x05,
+        Instruction::MUL => 0x06,
+        Instruction::SUB => 0x07,
+        Instruction::DIV => 0x08,
+        Instruction::CEQ => 0x09,
+        Instruction::CGT => 0x0A,
+        Instruction::CLT => 0x0B,
+        Instruction::DUP => 0x0C,
+        Instruction::POP => 0x0D,
+        Instruction::NOP => 0x0E,
+        Instruction::RNG => 0x0F,
+        Instruction::BR(_) => 0x10,
+        Instruction::BRTRUE(_) => 0x11,
+        Instruction::BRFALSE(_) => 0x12,
+        Instruction::RNGI => 0x13,
+        Instruction::RNGN => 0x14,
+        Instruction::RNGE => 0x15,
+        Instruction::SHUFFLE(_) => 0x16,
+        Instruction::CHOOSE(_) => 0x17,
+    }
+}
+
+fn operand_len(instruction: &Instruction) -> usize {
+    match instruction {
+        Instruction::LDC(_) => 8,
+        Instruction::LDV(_)
+        | Instruction::STV(_)
+        | Instruction::BR(_)
+        | Instruction::SHUFFLE(_)
+        | Instruction::CHOOSE(_)
+        | Instruction::BRTRUE(_)
+        | Instruction::BRFALSE(_) => 4,
+        _ => 0,
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position + len)
+            .ok_or(DecodeError::UnexpectedEnd(self.bytes.len()))?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn f64(&mut self) -> Result<f64, DecodeError> {
+        let mut value = [0; 8];
+        value.copy_from_slice(self.take(8)?);
+        Ok(f64::from_le_bytes(value))
+    }
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnexpectedEnd(usize),
+    UnknownOpcode { position: usize, opcode: u8 },
+    RegisterOutOfRange { position: usize, register: usize },
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::BadMagic => write!(f, "Not a stackalc bytecode file"),
+            DecodeError::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported bytecode version {} (expected {})",
+                version, VERSION
+            ),
+            DecodeError::UnexpectedEnd(position) => {
+                write!(f, "Bytecode ends unexpectedly at byte {:#06X}", position)
+            }
+            DecodeError::UnknownOpcode { position, opcode } => write!(
+                f,
+                "Unknown opcode {:#04X} at byte {:#06X}",
+                opcode, position
+            ),
+            DecodeError::RegisterOutOfRange { position, register } => write!(
+                f,
+                "Register r{} at byte {:#06X} is outside the declared registers",
+                register, position
+            ),
+            DecodeError::TrailingBytes(position) => {
+                write!(
+                    f,
+                    "Unexpected data after the last instruction at byte {:#06X}",
+                    position
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[derive(Debug)]
+pub enum EncodeError {
+    TooLarge(usize),
+    RegisterOutOfRange { offset: usize, register: usize },
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncodeError::TooLarge(value) => {
+                write!(f, "{} does not fit in a 32-bit bytecode field", value)
+            }
+            EncodeError::RegisterOutOfRange { offset, register } => write!(
+                f,
+                "Register r{} at {:#06X} is outside the register file",
+                register, offset
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_reverses_encode() {
+        let expr = vec![
+            Instruction::LDC(-2.5),
+            Instruction::STV(31),
+            Instruction::LDV(31),
+            Instruction::RNGI,
+            Instruction::SHUFFLE(2),
+            Instruction::CHOOSE(3),
+            Instruction::BRTRUE(8),
+            Instruction::BR(0),
+            Instruction::BRFALSE(9),
+        ];
+
+        let decoded = decode(&encode(&expr, 32).unwrap()).unwrap();
+        assert_eq!(decoded.registers, 32);
+        assert_eq!(decoded.expr, expr);
+    }
+
+    #[test]
+    fn encode_rejects_registers_decode_would() {
+        let expr = [Instruction::LDC(1.0), Instruction::STV(4)];
+        assert!(matches!(
+            encode(&expr, 4),
+            Err(EncodeError::RegisterOutOfRange {
+                offset: 1,
+                register: 4
+            })
+        ));
+    }
+}
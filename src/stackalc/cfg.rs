@@ -0,0 +1,223 @@
+use crate::stackalc::instructions::Instruction;
+use std::fmt;
+use std::fmt::Write;
+
+#[derive(Clone, Debug)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub end: usize,
+    pub successors: Vec<Edge>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Edge {
+    pub target: Successor,
+    pub kind: EdgeKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Successor {
+    Block(usize),
+    Exit,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EdgeKind {
+    Fallthrough,
+    Jump,
+    True,
+    False,
+}
+
+impl EdgeKind {
+    fn label(self) -> Option<&'static str> {
+        match self {
+            EdgeKind::True => Some("true"),
+            EdgeKind::False => Some("false"),
+            EdgeKind::Fallthrough | EdgeKind::Jump => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+}
+
+impl Cfg {
+    pub fn build(expr: &[Instruction]) -> Cfg {
+        let mut leaders = vec![false; expr.len()];
+        if !expr.is_empty() {
+            leaders[0] = true;
+        }
+        for (offset, instruction) in expr.iter().enumerate() {
+            if let Some(target) = instruction.branch_target() {
+                if target < expr.len() {
+                    leaders[target] = true;
+                }
+                if offset + 1 < expr.len() {
+                    leaders[offset + 1] = true;
+                }
+            }
+        }
+
+        let starts: Vec<usize> = (0..expr.len()).filter(|&i| leaders[i]).collect();
+        let block_at = |offset: usize| match starts.binary_search(&offset) {
+            Ok(block) => Successor::Block(block),
+            Err(_) => Successor::Exit,
+        };
+
+        let blocks = starts
+            .iter()
+            .enumerate()
+            .map(|(block, &start)| {
+                let end = starts.get(block + 1).copied().unwrap_or(expr.len());
+                let edge = |offset: usize, kind| Edge {
+                    target: block_at(offset),
+                    kind,
+                };
+
+                // A conditional branch continues at the next offset, then at its target. Branches
+                // the VM ignores have only the next offset, so they become plain fallthroughs.
+                let last = &expr[end - 1];
+                let successors = match (last, last.successors(end - 1, expr.len()).as_slice()) {
+                    (Instruction::BR(target), [next]) if next == target => {
+                        vec![edge(*target, EdgeKind::Jump)]
+                    }
+                    (Instruction::BRTRUE(_), [next, target]) => {
+                        vec![edge(*target, EdgeKind::True), edge(*next, EdgeKind::False)]
+                    }
+                    (Instruction::BRFALSE(_), [next, target]) => {
+                        vec![edge(*target, EdgeKind::False), edge(*next, EdgeKind::True)]
+                    }
+                    (_, offsets) => offsets
+                        .iter()
+                        .map(|offset| edge(*offset, EdgeKind::Fallthrough))
+                        .collect(),
+                };
+
+                BasicBlock {
+                    start,
+                    end,
+                    successors,
+                }
+            })
+            .collect();
+
+        Cfg { blocks }
+    }
+
+    pub fn block_of(&self, offset: usize) -> Option<usize> {
+        self.blocks
+            .iter()
+            .position(|block| (block.start..block.end).contains(&offset))
+    }
+
+    pub fn to_dot(&self, expr: &[Instruction]) -> String {
+        let mut dot = String::from("digraph program {\n");
+        dot.push_str("    node [shape=box, fontname=\"monospace\"];\n");
+        dot.push_str("    entry [shape=point];\n");
+        dot.push_str("    exit [shape=doublecircle, label=\"exit\"];\n\n");
+
+        for (id, block) in self.blocks.iter().enumerate() {
+            let mut label = format!("B{}\\l", id);
+            for (offset, instruction) in expr.iter().enumerate().take(block.end).skip(block.start) {
+                let _ = write!(label, "{:#06X}  {}\\l", offset, instruction);
+            }
+            let _ = writeln!(dot, "    B{} [label=\"{}\"];", id, label);
+        }
+        dot.push('\n');
+
+        let entry = if self.blocks.is_empty() { "exit" } else { "B0" };
+        let _ = writeln!(dot, "    entry -> {};", entry);
+
+        for (id, block) in self.blocks.iter().enumerate() {
+            for edge in &block.successors {
+                let _ = match edge.kind.label() {
+                    Some(label) => {
+                        writeln!(dot, "    B{} -> {} [label=\"{}\"];", id, edge.target, label)
+                    }
+                    None => writeln!(dot, "    B{} -> {};", id, edge.target),
+                };
+            }
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+impl fmt::Display for Successor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Successor::Block(block) => write!(f, "B{}", block),
+            Successor::Exit => write!(f, "exit"),
+        }
+    }
+}
+
+impl fmt::Display for Edge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind.label() {
+            Some(label) => write!(f, "{} ({})", self.target, label),
+            None => write!(f, "{}", self.target),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stackalc::{CalcMode, Stackalc};
+
+    fn parse(source: &str) -> Vec<Instruction> {
+        let mut stackalc = Stackalc::default();
+        stackalc.parse(CalcMode::RAW, source).unwrap();
+        stackalc.expr
+    }
+
+    #[test]
+    fn branches_split_the_program_into_blocks() {
+        let expr = parse("ldc:1 brtrue:4 ldc:2 br:5 ldc:3 nop");
+        let cfg = Cfg::build(&expr);
+
+        let bounds: Vec<(usize, usize)> = cfg
+            .blocks
+            .iter()
+            .map(|block| (block.start, block.end))
+            .collect();
+        assert_eq!(bounds, [(0, 2), (2, 4), (4, 5), (5, 6)]);
+        assert_eq!(cfg.block_of(3), Some(1));
+
+        let edge = |block, kind| Edge {
+            target: Successor::Block(block),
+            kind,
+        };
+        assert_eq!(
+            cfg.blocks[0].successors,
+            [edge(2, EdgeKind::True), edge(1, EdgeKind::False)]
+        );
+        assert_eq!(cfg.blocks[1].successors, [edge(3, EdgeKind::Jump)]);
+        assert_eq!(cfg.blocks[2].successors, [edge(3, EdgeKind::Fallthrough)]);
+        assert_eq!(
+            cfg.blocks[3].successors,
+            [Edge {
+                target: Successor::Exit,
+                kind: EdgeKind::Fallthrough
+            }]
+        );
+
+        let dot = cfg.to_dot(&expr);
+        assert!(dot.contains("    entry -> B0;\n"));
+        assert!(dot.contains("    B0 -> B2 [label=\"true\"];\n"));
+        assert!(dot.contains("    B3 -> exit;\n"));
+    }
+
+    #[test]
+    fn an_empty_program_goes_straight_to_the_exit() {
+        let cfg = Cfg::build(&[]);
+
+        assert!(cfg.blocks.is_empty());
+        assert!(cfg.to_dot(&[]).contains("    entry -> exit;\n"));
+    }
+}
@@ -0,0 +1,251 @@
+use crate::stackalc::instructions::Instruction;
+use std::collections::HashMap;
+use std::ops::Range;
+
+pub type Spanned = (Instruction, Range<usize>);
+
+/// Expression trees recovered from naive postfix code, where every instruction either pushes a
+/// leaf value or combines the top two values.
+struct Forest<'a> {
+    code: &'a [Spanned],
+    operands: Vec<Option<(usize, usize)>>,
+    starts: Vec<usize>,
+    roots: Vec<usize>,
+}
+
+impl<'a> Forest<'a> {
+    fn new(code: &'a [Spanned]) -> Option<Self> {
+        let mut operands = Vec::with_capacity(code.len());
+        let mut starts = Vec::with_capacity(code.len());
+        let mut stack = Vec::new();
+
+        for (node, (instruction, _)) in code.iter().enumerate() {
+            match instruction.stack_effect() {
+                (0, 1) => {
+                    operands.push(None);
+                    starts.push(node);
+                }
+                (2, 1) => {
+                    let pushed_second = stack.pop()?;
+                    let pushed_first = stack.pop()?;
+                    operands.push(Some((pushed_first, pushed_second)));
+                    starts.push(starts[pushed_first]);
+                }
+                _ => return None,
+            }
+            stack.push(node);
+        }
+
+        Some(Forest {
+            code,
+            operands,
+            starts,
+            roots: stack,
+        })
+    }
+
+    fn instructions(&self, node: usize) -> &[Spanned] {
+        &self.code[self.starts[node]..=node]
+    }
+
+    fn key(&self, node: usize) -> String {
+        self.instructions(node)
+            .iter()
+            .map(|(instruction, _)| instruction.to_string())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn span(&self, node: usize) -> Range<usize> {
+        let spans = self.instructions(node).iter().map(|(_, span)| span);
+        let start = spans.clone().map(|span| span.start).min().unwrap_or(0);
+        let end = spans.map(|span| span.end).max().unwrap_or(0);
+        start..end
+    }
+
+    fn is_pure(&self, node: usize) -> bool {
+        !self
+            .instructions(node)
+            .iter()
+            .any(|(instruction, _)| instruction.is_random())
+    }
+}
+
+/// Sethi-Ullman ordering: operands of commutative operators are swapped so the one needing more
+/// stack slots is evaluated first, which keeps the peak depth of every subtree to a minimum.
+pub fn minimise_stack_depth(code: Vec<Spanned>) -> Vec<Spanned> {
+    let Some(forest) = Forest::new(&code) else {
+        return code;
+    };
+
+    let mut needs = vec![0; code.len()];
+    for node in 0..code.len() {
+        needs[node] = match forest.operands[node] {
+            None => 1,
+            Some((pushed_first, pushed_second)) => {
+                let (first, second) = (needs[pushed_first], needs[pushed_second]);
+                if is_commutative(&code[node].0) {
+                    first.max(second).max(first.min(second) + 1)
+                } else {
+                    first.max(second + 1)
+                }
+            }
+        };
+    }
+
+    let mut output = Vec::with_capacity(code.len());
+    for &root in &forest.roots {
+        emit_reordered(&forest, &needs, root, &mut output);
+    }
+    output
+}
+
+fn is_commutative(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::ADD | Instruction::MUL | Instruction::CEQ
+    )
+}
+
+fn emit_reordered(forest: &Forest, needs: &[usize], node: usize, output: &mut Vec<Spanned>) {
+    if let Some((pushed_first, pushed_second)) = forest.operands[node] {
+        let swap =
+            is_commutative(&forest.code[node].0) && needs[pushed_second] > needs[pushed_first];
+        let (first, second) = if swap {
+            (pushed_second, pushed_first)
+        } else {
+            (pushed_first, pushed_second)
+        };
+        emit_reordered(forest, needs, first, output);
+        emit_reordered(forest, needs, second, output);
+    }
+    output.push(forest.code[node].clone());
+}
+
+/// Computes every repeated subtree once, keeping a copy in a free register with `dup; stv` and
+/// reloading it with `ldv` wherever it appears again.
+pub fn eliminate_common_subexpressions(
+    code: Vec<Spanned>,
+    free_registers: &[usize],
+) -> Vec<Spanned> {
+    let Some(forest) = Forest::new(&code) else {
+        return code;
+    };
+
+    // Occurrences are counted as they would be emitted: once a subtree has been seen, later
+    // copies are reloaded whole, so their inner nodes are never evaluated again.
+    let mut counts = HashMap::new();
+    for &root in &forest.roots {
+        count_subtrees(&forest, root, &mut counts);
+    }
+
+    let mut cse = CommonSubexpressions {
+        forest: &forest,
+        counts,
+        stored: HashMap::new(),
+        free_registers: free_registers.iter(),
+        output: Vec::new(),
+    };
+    for &root in &forest.roots {
+        cse.emit(root);
+    }
+
+    cse.output
+}
+
+fn count_subtrees(forest: &Forest, node: usize, counts: &mut HashMap<String, usize>) {
+    let Some((pushed_first, pushed_second)) = forest.operands[node] else {
+        return;
+    };
+
+    let count = counts.entry(forest.key(node)).or_insert(0);
+    *count += 1;
+    if *count == 1 {
+        count_subtrees(forest, pushed_first, counts);
+        count_subtrees(forest, pushed_second, counts);
+    }
+}
+
+struct CommonSubexpressions<'a, 'f> {
+    forest: &'a Forest<'f>,
+    counts: HashMap<String, usize>,
+    stored: HashMap<String, usize>,
+    free_registers: std::slice::Iter<'a, usize>,
+    output: Vec<Spanned>,
+}
+
+impl CommonSubexpressions<'_, '_> {
+    fn emit(&mut self, node: usize) {
+        let forest = self.forest;
+        let Some((pushed_first, pushed_second)) = forest.operands[node] else {
+            self.output.push(forest.code[node].clone());
+            return;
+        };
+
+        let key = forest.key(node);
+        let span = forest.span(node);
+        if let Some(&register) = self.stored.get(&key) {
+            self.output.push((Instruction::LDV(register), span));
+            return;
+        }
+
+        self.emit(pushed_first);
+        self.emit(pushed_second);
+        self.output.push(forest.code[node].clone());
+
+        if self.counts[&key] > 1
+            && forest.is_pure(node)
+            && let Some(&register) = self.free_registers.next()
+        {
+            self.output.push((Instruction::DUP, span.clone()));
+            self.output.push((Instruction::STV(register), span));
+            self.stored.insert(key, register);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stackalc::instructions::Instruction;
+    use crate::stackalc::{CalcMode, Stackalc};
+
+    fn evaluate(input: &str, cse: bool, reorder: bool) -> (Vec<Instruction>, Vec<f64>) {
+        let mut stackalc = Stackalc {
+            cse,
+            reorder,
+            ..Stackalc::default()
+        };
+        stackalc.parse(CalcMode::INFIX, input).unwrap();
+        stackalc.run_to_completion(1000).unwrap();
+        (stackalc.expr, stackalc.stack)
+    }
+
+    #[test]
+    fn common_subexpressions_keep_the_value() {
+        let input = "(9 - 3) / (9 - 3) - 8 / (2 - 4 * (9 - 3))";
+        let (plain, expected) = evaluate(input, false, false);
+        assert_eq!(expected, [1.0 + 8.0 / 22.0]);
+
+        for reorder in [false, true] {
+            let (expr, stack) = evaluate(input, true, reorder);
+            assert!(expr.len() < plain.len());
+            let stores: Vec<&Instruction> = expr
+                .iter()
+                .filter(|instruction| matches!(instruction, Instruction::STV(_)))
+                .collect();
+            assert_eq!(stores, [&Instruction::STV(31)]);
+            assert_eq!(stack, expected);
+        }
+    }
+
+    #[test]
+    fn reordering_lowers_the_peak_depth_and_keeps_the_value() {
+        let input = "1 - 2 * (3 + (4 + 5))";
+        let stackalc = Stackalc::default();
+        assert_eq!(stackalc.infix_peak_depths(input), Some((5, 3)));
+
+        let (_, expected) = evaluate(input, false, false);
+        assert_eq!(expected, [-23.0]);
+        assert_eq!(evaluate(input, false, true).1, expected);
+    }
+}
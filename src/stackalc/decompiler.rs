@@ -0,0 +1,250 @@
+use crate::stackalc::ast::{Ast, BinaryOp};
+use crate::stackalc::instructions::Instruction;
+use std::fmt;
+
+#[derive(Clone, Debug)]
+pub struct Decompiled {
+    pub statements: Vec<(usize, Ast)>,
+    pub results: Vec<Ast>,
+}
+
+impl Decompiled {
+    pub fn infix(&self) -> String {
+        let statements = self
+            .statements
+            .iter()
+            .map(|(register, value)| format!("r{} = {}", register, value));
+        let results = self.results.iter().map(|value| value.to_string());
+
+        statements.chain(results).collect::<Vec<_>>().join("; ")
+    }
+
+    pub fn postfix(&self) -> String {
+        let statements = self
+            .statements
+            .iter()
+            .map(|(register, value)| format!("{} stv:{}", value.postfix(), register));
+        let results = self.results.iter().map(|value| value.postfix());
+
+        statements.chain(results).collect::<Vec<_>>().join("; ")
+    }
+}
+
+pub fn decompile(expr: &[Instruction]) -> Result<Decompiled, DecompileError> {
+    if expr.is_empty() {
+        return Err(DecompileError::EmptyProgram);
+    }
+
+    let mut decompiled = Decompiled {
+        statements: Vec::new(),
+        results: Vec::new(),
+    };
+    let mut stack = Vec::new();
+
+    execute(expr, 0, expr.len(), &mut stack, &mut decompiled.statements)?;
+    decompiled.results = stack;
+
+    Ok(decompiled)
+}
+
+fn execute(
+    expr: &[Instruction],
+    start: usize,
+    end: usize,
+    stack: &mut Vec<Ast>,
+    statements: &mut Vec<(usize, Ast)>,
+) -> Result<(), DecompileError> {
+    let mut offset = start;
+
+    while offset < end {
+        let instruction = &expr[offset];
+        let pop = |stack: &mut Vec<Ast>| stack.pop().ok_or(DecompileError::StackUnderflow(offset));
+
+        match instruction {
+            Instruction::LDC(n) => stack.push(Ast::Num(*n)),
+            Instruction::LDV(n) => stack.push(Ast::Register(*n)),
+            Instruction::RNG => stack.push(Ast::Rng),
+            Instruction::RNGN => stack.push(Ast::Normal),
+            Instruction::RNGE => stack.push(Ast::Exponential),
+            Instruction::RNGI => {
+                let rhs = pop(stack)?;
+                let lhs = pop(stack)?;
+                stack.push(Ast::RandomInt(Box::new(lhs), Box::new(rhs)));
+            }
+            Instruction::CHOOSE(0) => {}
+            Instruction::CHOOSE(n) => {
+                if stack.len() < *n {
+                    return Err(DecompileError::StackUnderflow(offset));
+                }
+                let candidates = stack.split_off(stack.len() - n);
+                stack.push(Ast::Choose(candidates));
+            }
+            // Which value ends up where is only known at run time.
+            Instruction::SHUFFLE(_) => return Err(DecompileError::UnsupportedInstruction(offset)),
+            Instruction::STV(n) => {
+                let value = pop(stack)?;
+                statements.push((*n, value));
+            }
+            Instruction::NEG => {
+                let value = pop(stack)?;
+                stack.push(Ast::Neg(Box::new(value)));
+            }
+            Instruction::DUP => {
+                let value = pop(stack)?;
+                stack.push(value.clone());
+                stack.push(value);
+            }
+            Instruction::POP => {
+                pop(stack)?;
+            }
+            Instruction::NOP => {}
+            Instruction::BRTRUE(target) | Instruction::BRFALSE(target) => {
+                let condition = pop(stack)?;
+                let (jumped, fallen_through, resume) =
+                    conditional(expr, offset, *target, end, stack)?;
+
+                let (when_true, when_false) = match instruction {
+                    Instruction::BRTRUE(_) => (jumped, fallen_through),
+                    _ => (fallen_through, jumped),
+                };
+                stack.push(Ast::Conditional(
+                    Box::new(condition),
+                    Box::new(when_true),
+                    Box::new(when_false),
+                ));
+                offset = resume;
+                continue;
+            }
+            Instruction::BR(_) => return Err(DecompileError::UnsupportedBranch(offset)),
+            Instruction::ADD
+            | Instruction::SUB
+            | Instruction::MUL
+            | Instruction::DIV
+            | Instruction::CEQ
+            | Instruction::CGT
+            | Instruction::CLT => {
+                let op = BinaryOp::from_instruction(instruction).unwrap();
+                let rhs = pop(stack)?;
+                let lhs = pop(stack)?;
+                stack.push(Ast::Binary(op, Box::new(lhs), Box::new(rhs)));
+            }
+        }
+
+        offset += 1;
+    }
+
+    Ok(())
+}
+
+/// Matches `cond; br?:T; <fallthrough arm>; br:E; T: <jump arm>; E:` where each arm pushes
+/// exactly one value and touches nothing else. Returns both arms and the offset `E`.
+fn conditional(
+    expr: &[Instruction],
+    offset: usize,
+    target: usize,
+    end: usize,
+    stack: &[Ast],
+) -> Result<(Ast, Ast, usize), DecompileError> {
+    let unsupported = DecompileError::UnsupportedBranch(offset);
+
+    if target <= offset + 1 || target > end {
+        return Err(unsupported);
+    }
+    let resume = match expr[target - 1] {
+        Instruction::BR(resume) if resume >= target && resume <= end => resume,
+        _ => return Err(unsupported),
+    };
+
+    let arm = |start: usize, end: usize| -> Result<Ast, DecompileError> {
+        let mut arm_stack = stack.to_vec();
+        let mut arm_statements = Vec::new();
+        execute(expr, start, end, &mut arm_stack, &mut arm_statements)?;
+
+        if !arm_statements.is_empty()
+            || arm_stack.len() != stack.len() + 1
+            || arm_stack[..stack.len()] != *stack
+        {
+            return Err(DecompileError::UnsupportedBranch(offset));
+        }
+        Ok(arm_stack.pop().unwrap())
+    };
+
+    let fallen_through = arm(offset + 1, target - 1)?;
+    let jumped = arm(target, resume)?;
+
+    Ok((jumped, fallen_through, resume))
+}
+
+#[derive(Clone, Debug)]
+pub enum DecompileError {
+    EmptyProgram,
+    StackUnderflow(usize),
+    UnsupportedBranch(usize),
+    UnsupportedInstruction(usize),
+}
+
+impl fmt::Display for DecompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecompileError::EmptyProgram => write!(f, "Empty program"),
+            DecompileError::StackUnderflow(offset) => {
+                write!(f, "Stack underflow at {:#06X}", offset)
+            }
+            DecompileError::UnsupportedBranch(offset) => {
+                write!(f, "Unsupported branch pattern at {:#06X}", offset)
+            }
+            DecompileError::UnsupportedInstruction(offset) => {
+                write!(f, "No expression for the instruction at {:#06X}", offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecompileError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stackalc::{CalcMode, Stackalc};
+
+    fn decompile_raw(source: &str) -> Result<Decompiled, DecompileError> {
+        let mut stackalc = Stackalc::default();
+        stackalc.parse(CalcMode::RAW, source).unwrap();
+        decompile(&stackalc.expr)
+    }
+
+    #[test]
+    fn expressions_come_back_as_infix_and_postfix() {
+        let decompiled = decompile_raw("ldc:10 ldc:4 ldc:2 mul sub dup stv:0 ldv:0 neg").unwrap();
+
+        assert_eq!(decompiled.infix(), "r0 = 10 - 4 * 2; 10 - 4 * 2; -r0");
+        assert_eq!(decompiled.postfix(), "10 4 2 * - stv:0; 10 4 2 * -; r0 neg");
+    }
+
+    #[test]
+    fn conditionals_become_a_single_value() {
+        let decompiled = decompile_raw("ldc:1 ldc:2 clt brtrue:6 ldc:3 br:7 ldc:4").unwrap();
+
+        assert_eq!(decompiled.infix(), "1 <= 2 ? 4 : 3");
+    }
+
+    #[test]
+    fn unsupported_code_is_reported() {
+        assert!(matches!(
+            decompile_raw(""),
+            Err(DecompileError::EmptyProgram)
+        ));
+        assert!(matches!(
+            decompile_raw("ldc:1 add"),
+            Err(DecompileError::StackUnderflow(1))
+        ));
+        assert!(matches!(
+            decompile_raw("ldc:1 ldc:2 shuffle:2"),
+            Err(DecompileError::UnsupportedInstruction(2))
+        ));
+        assert!(matches!(
+            decompile_raw("br:1"),
+            Err(DecompileError::UnsupportedBranch(0))
+        ));
+    }
+}
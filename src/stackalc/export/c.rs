@@ -0,0 +1,336 @@
+use crate::stackalc::export::{ExportError, branch_targets, used_registers, verified};
+use crate::stackalc::instructions::Instruction;
+use std::fmt::Write;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Translation {
+    /// Every instruction works on an explicit `double stack[]` through a stack pointer.
+    Direct,
+    /// Stack slots become locals `s0`, `s1`, ..., picked statically from the verified depths.
+    Promoted,
+}
+
+/// Emits a self-contained C function `stackalc_run` that returns what the VM leaves on top of
+/// the stack, or `0` for an empty stack. Registers start out as `0` and branches become `goto`s.
+pub fn export(
+    expr: &[Instruction],
+    registers: usize,
+    translation: Translation,
+) -> Result<String, ExportError> {
+    let verification = verified(expr, registers)?;
+    let targets = branch_targets(expr);
+    let uses_rng = expr.iter().any(Instruction::is_random);
+    let uses_math = expr.iter().any(|instruction| {
+        matches!(
+            instruction,
+            Instruction::RNGI | Instruction::RNGN | Instruction::RNGE
+        ) || matches!(instruction, Instruction::LDC(value) if !value.is_finite())
+    });
+
+    let mut c = String::from("/* Generated by stackalc */\n");
+    if uses_math {
+        c.push_str("#include <math.h>\n");
+    }
+    if uses_rng {
+        c.push_str("#include <stdlib.h>\n");
+    }
+    if uses_math || uses_rng {
+        c.push('\n');
+    }
+    for helper in HELPERS {
+        if expr.iter().any(|instruction| (helper.used_by)(instruction)) {
+            c.push_str(helper.source);
+            c.push('\n');
+        }
+    }
+    c.push_str("double stackalc_run(void)\n{\n");
+
+    let max_depth = verification.max_depth();
+    let used = used_registers(expr);
+    match translation {
+        Translation::Direct => {
+            if !used.is_empty() {
+                let _ = writeln!(c, "    double r[{}] = {{0}};", registers);
+            }
+            let _ = writeln!(c, "    double stack[{}];", max_depth.max(1));
+            c.push_str("    int sp = 0;\n");
+        }
+        Translation::Promoted => {
+            if !used.is_empty() {
+                let locals: Vec<String> = used
+                    .iter()
+                    .map(|register| format!("r{} = 0.0", register))
+                    .collect();
+                let _ = writeln!(c, "    double {};", locals.join(", "));
+            }
+            if max_depth > 0 {
+                let slots: Vec<String> = (0..max_depth).map(|slot| format!("s{}", slot)).collect();
+                let _ = writeln!(c, "    double {};", slots.join(", "));
+            }
+        }
+    }
+    c.push('\n');
+
+    for (offset, instruction) in expr.iter().enumerate() {
+        if targets.contains(&offset) {
+            let _ = writeln!(c, "{}:", label(offset));
+        }
+        let _ = writeln!(c, "    /* {:#06X}  {} */", offset, instruction);
+        let line = match translation {
+            Translation::Direct => Some(direct(instruction)),
+            Translation::Promoted => {
+                verification.depths[offset].and_then(|depth| promoted(instruction, depth))
+            }
+        };
+        if let Some(line) = line {
+            let _ = writeln!(c, "    {}", line);
+        }
+    }
+
+    if targets.contains(&expr.len()) {
+        let _ = writeln!(c, "{}:", label(expr.len()));
+    }
+    let result = match (translation, verification.depths[expr.len()].unwrap_or(0)) {
+        (Translation::Direct, _) => String::from("sp > 0 ? stack[sp - 1] : 0.0"),
+        (Translation::Promoted, 0) => String::from("0.0"),
+        (Translation::Promoted, depth) => format!("s{}", depth - 1),
+    };
+    let _ = writeln!(c, "    return {};\n}}", result);
+
+    Ok(c)
+}
+
+fn label(offset: usize) -> String {
+    format!("L_{:04X}", offset)
+}
+
+fn direct(instruction: &Instruction) -> String {
+    // After `sp--` the top of the stack is `stack[sp]` and the value below it `stack[sp - 1]`.
+    let binary = |op: &str| format!("sp--; stack[sp - 1] = stack[sp - 1] {} stack[sp];", op);
+
+    match instruction {
+        Instruction::LDC(value) => format!("stack[sp++] = {};", float_literal(*value)),
+        Instruction::LDV(register) => format!("stack[sp++] = r[{}];", register),
+        Instruction::STV(register) => format!("r[{}] = stack[--sp];", register),
+        Instruction::NEG => String::from("stack[sp - 1] = -stack[sp - 1];"),
+        Instruction::ADD => binary("+"),
+        Instruction::MUL => binary("*"),
+        Instruction::SUB => binary("-"),
+        Instruction::DIV => binary("/"),
+        Instruction::CEQ => binary("=="),
+        Instruction::CGT => binary(">="),
+        Instruction::CLT => binary("<="),
+        Instruction::DUP => String::from("stack[sp] = stack[sp - 1]; sp++;"),
+        Instruction::POP => String::from("sp--;"),
+        Instruction::NOP => String::from(";"),
+        Instruction::RNG => format!("stack[sp++] = {};", RANDOM),
+        Instruction::RNGI => {
+            String::from("sp--; stack[sp - 1] = stackalc_rngi(stack[sp], stack[sp - 1]);")
+        }
+        Instruction::RNGN => String::from("stack[sp++] = stackalc_rngn();"),
+        Instruction::RNGE => String::from("stack[sp++] = stackalc_rnge();"),
+        Instruction::SHUFFLE(n) => format!("stackalc_shuffle(&stack[sp - {n}], {n});", n = n),
+        Instruction::CHOOSE(0) => String::from(";"),
+        Instruction::CHOOSE(n) => format!(
+            "sp -= {n}; stack[sp] = stackalc_choose(&stack[sp], {n}); sp++;",
+            n = n
+        ),
+        Instruction::BR(target) => format!("goto {};", label(*target)),
+        Instruction::BRTRUE(target) => {
+            format!("if (stack[--sp] != 0.0) goto {};", label(*target))
+        }
+        Instruction::BRFALSE(target) => {
+            format!("if (stack[--sp] == 0.0) goto {};", label(*target))
+        }
+    }
+}
+
+/// `depth` is the number of values on the stack before the instruction runs, so the top of the
+/// stack lives in `s{depth - 1}`. Instructions that only move the stack pointer emit nothing.
+fn promoted(instruction: &Instruction, depth: usize) -> Option<String> {
+    let top = || format!("s{}", depth - 1);
+    let binary = |op: &str| {
+        Some(format!(
+            "s{second} = s{second} {op} s{top};",
+            second = depth - 2,
+            top = depth - 1,
+            op = op
+        ))
+    };
+
+    match instruction {
+        Instruction::LDC(value) => Some(format!("s{} = {};", depth, float_literal(*value))),
+        Instruction::LDV(register) => Some(format!("s{} = r{};", depth, register)),
+        Instruction::STV(register) => Some(format!("r{} = {};", register, top())),
+        Instruction::NEG => Some(format!("{top} = -{top};", top = top())),
+        Instruction::ADD => binary("+"),
+        Instruction::MUL => binary("*"),
+        Instruction::SUB => binary("-"),
+        Instruction::DIV => binary("/"),
+        Instruction::CEQ => binary("=="),
+        Instruction::CGT => binary(">="),
+        Instruction::CLT => binary("<="),
+        Instruction::DUP => Some(format!("s{} = {};", depth, top())),
+        Instruction::POP | Instruction::NOP => None,
+        Instruction::RNG => Some(format!("s{} = {};", depth, RANDOM)),
+        Instruction::RNGI => Some(format!(
+            "s{second} = stackalc_rngi(s{top}, s{second});",
+            second = depth - 2,
+            top = depth - 1
+        )),
+        Instruction::RNGN => Some(format!("s{} = stackalc_rngn();", depth)),
+        Instruction::RNGE => Some(format!("s{} = stackalc_rnge();", depth)),
+        // The slots are separate locals, so they go through an array and back.
+        Instruction::SHUFFLE(0) | Instruction::CHOOSE(0) => None,
+        Instruction::SHUFFLE(n) => {
+            let slots = slots(depth - n..depth);
+            let stores: Vec<String> = (depth - n..depth)
+                .enumerate()
+                .map(|(index, slot)| format!("s{} = v[{}];", slot, index))
+                .collect();
+            Some(format!(
+                "{{ double v[] = {{{}}}; stackalc_shuffle(v, {}); {} }}",
+                slots,
+                n,
+                stores.join(" ")
+            ))
+        }
+        Instruction::CHOOSE(n) => Some(format!(
+            "{{ double v[] = {{{}}}; s{} = stackalc_choose(v, {}); }}",
+            slots(depth - n..depth),
+            depth - n,
+            n
+        )),
+        Instruction::BR(target) => Some(format!("goto {};", label(*target))),
+        Instruction::BRTRUE(target) => {
+            Some(format!("if ({} != 0.0) goto {};", top(), label(*target)))
+        }
+        Instruction::BRFALSE(target) => {
+            Some(format!("if ({} == 0.0) goto {};", top(), label(*target)))
+        }
+    }
+}
+
+fn slots(range: std::ops::Range<usize>) -> String {
+    range
+        .map(|slot| format!("s{}", slot))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+const RANDOM: &str = "rand() / ((double)RAND_MAX + 1.0)";
+
+/// A support function emitted ahead of `stackalc_run` when an instruction needs it.
+struct Helper {
+    used_by: fn(&Instruction) -> bool,
+    source: &'static str,
+}
+
+const HELPERS: [Helper; 5] = [
+    Helper {
+        used_by: |instruction| matches!(instruction, Instruction::RNGI),
+        source: "\
+static double stackalc_rngi(double a, double b)
+{
+    double low = ceil(fmin(a, b)), high = floor(fmax(a, b));
+    if (!isfinite(low) || !isfinite(high) || low > high)
+        return NAN;
+    return low + floor(rand() / ((double)RAND_MAX + 1.0) * (high - low + 1.0));
+}
+",
+    },
+    Helper {
+        used_by: |instruction| matches!(instruction, Instruction::RNGN),
+        source: "\
+static double stackalc_rngn(void)
+{
+    double u1 = 1.0 - rand() / ((double)RAND_MAX + 1.0);
+    double u2 = rand() / ((double)RAND_MAX + 1.0);
+    return sqrt(-2.0 * log(u1)) * cos(6.283185307179586 * u2);
+}
+",
+    },
+    Helper {
+        used_by: |instruction| matches!(instruction, Instruction::RNGE),
+        source: "\
+static double stackalc_rnge(void)
+{
+    return -log(1.0 - rand() / ((double)RAND_MAX + 1.0));
+}
+",
+    },
+    Helper {
+        used_by: |instruction| matches!(instruction, Instruction::SHUFFLE(_)),
+        source: "\
+static void stackalc_shuffle(double *v, int n)
+{
+    for (int i = n - 1; i > 0; i--) {
+        int j = (int)(rand() / ((double)RAND_MAX + 1.0) * (i + 1));
+        double t = v[i];
+        v[i] = v[j];
+        v[j] = t;
+    }
+}
+",
+    },
+    Helper {
+        used_by: |instruction| matches!(instruction, Instruction::CHOOSE(_)),
+        source: "\
+static double stackalc_choose(const double *v, int n)
+{
+    return v[(int)(rand() / ((double)RAND_MAX + 1.0) * n)];
+}
+",
+    },
+];
+
+fn float_literal(value: f64) -> String {
+    if value.is_nan() {
+        String::from("NAN")
+    } else if value.is_infinite() {
+        String::from(if value > 0.0 { "INFINITY" } else { "-INFINITY" })
+    } else {
+        format!("{:?}", value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stackalc::{CalcMode, Stackalc};
+
+    fn export_raw(source: &str, translation: Translation) -> String {
+        let mut stackalc = Stackalc::default();
+        stackalc.parse(CalcMode::RAW, source).unwrap();
+        export(&stackalc.expr, 4, translation).unwrap()
+    }
+
+    #[test]
+    fn both_translations_keep_the_operand_order() {
+        let source = "ldc:10 ldc:4 sub stv:3 ldv:3 ldc:2 cgt";
+
+        let direct = export_raw(source, Translation::Direct);
+        assert!(direct.contains("    double r[4] = {0};\n    double stack[2];\n"));
+        assert!(direct.contains("    sp--; stack[sp - 1] = stack[sp - 1] - stack[sp];\n"));
+        assert!(direct.contains("    sp--; stack[sp - 1] = stack[sp - 1] >= stack[sp];\n"));
+        assert!(direct.contains("    return sp > 0 ? stack[sp - 1] : 0.0;\n"));
+
+        let promoted = export_raw(source, Translation::Promoted);
+        assert!(promoted.contains("    double r3 = 0.0;\n    double s0, s1;\n"));
+        assert!(promoted.contains("    s0 = s0 - s1;\n    /* 0x0003  stv:3 */\n    r3 = s0;\n"));
+        assert!(promoted.contains("    s0 = s0 >= s1;\n    return s0;\n"));
+    }
+
+    #[test]
+    fn helpers_and_headers_are_only_emitted_when_needed() {
+        let plain = export_raw("ldc:1 ldc:2 add", Translation::Promoted);
+        assert!(plain.starts_with("/* Generated by stackalc */\ndouble stackalc_run(void)\n"));
+
+        let random = export_raw("ldc:1 ldc:6 rngi ldc:-inf", Translation::Promoted);
+        assert!(random.contains("#include <math.h>\n#include <stdlib.h>\n"));
+        assert!(random.contains("static double stackalc_rngi(double a, double b)\n"));
+        assert!(!random.contains("stackalc_choose"));
+        assert!(random.contains("    s0 = stackalc_rngi(s1, s0);\n    /* 0x0003  ldc:-inf */\n"));
+        assert!(random.contains("    s1 = -INFINITY;\n"));
+    }
+}
@@ -0,0 +1,173 @@
+use crate::stackalc::export::{ExportError, branch_targets, portable, verified};
+use crate::stackalc::instructions::Instruction;
+use std::fmt::Write;
+
+/// Emits an ilasm source file whose `Program::Run` computes what the VM leaves on top of the
+/// stack, or `0` for an empty stack. Registers become `float64` locals initialised to `0`.
+pub fn export(expr: &[Instruction], registers: usize) -> Result<String, ExportError> {
+    let verification = verified(expr, registers)?;
+    portable(expr)?;
+    let targets = branch_targets(expr);
+    let uses_rng = expr
+        .iter()
+        .any(|instruction| matches!(instruction, Instruction::RNG));
+
+    let mut il = String::new();
+    il.push_str("// Generated by stackalc\n");
+    il.push_str(".assembly extern mscorlib {}\n");
+    il.push_str(".assembly stackalc {}\n");
+    il.push_str(".module stackalc.exe\n\n");
+    il.push_str(".class public abstract sealed auto ansi beforefieldinit Program\n");
+    il.push_str("       extends [mscorlib]System.Object\n{\n");
+    il.push_str("    .method public hidebysig static float64 Run() cil managed\n    {\n");
+    // Conditional branches push a `0.0` to compare against and `rng` pushes the `Random` instance
+    // before calling it, one slot above the deepest verified stack.
+    let _ = writeln!(il, "        .maxstack {}", verification.max_depth() + 1);
+
+    let mut locals: Vec<String> = (0..registers)
+        .map(|register| format!("float64 r{}", register))
+        .collect();
+    locals.push(String::from("float64 tmp0"));
+    if uses_rng {
+        locals.push(String::from("class [mscorlib]System.Random random"));
+    }
+    let _ = writeln!(
+        il,
+        "        .locals init (\n            {}\n        )\n",
+        locals.join(",\n            ")
+    );
+
+    if uses_rng {
+        il.push_str("        newobj instance void [mscorlib]System.Random::.ctor()\n");
+        il.push_str("        stloc random\n\n");
+    }
+
+    for (offset, instruction) in expr.iter().enumerate() {
+        if targets.contains(&offset) {
+            let _ = writeln!(il, "    {}:", label(offset));
+        }
+        let _ = writeln!(il, "        // {:#06X}  {}", offset, instruction);
+        for line in translate(instruction) {
+            let _ = writeln!(il, "        {}", line);
+        }
+    }
+
+    let _ = writeln!(il, "    {}:", label(expr.len()));
+    let final_depth = verification.depths[expr.len()].unwrap_or(0);
+    match final_depth {
+        0 => il.push_str("        ldc.r8 0.0\n"),
+        1 => {}
+        depth => {
+            il.push_str("        stloc tmp0\n");
+            for _ in 1..depth {
+                il.push_str("        pop\n");
+            }
+            il.push_str("        ldloc tmp0\n");
+        }
+    }
+    il.push_str("        ret\n    }\n\n");
+
+    il.push_str("    .method public hidebysig static void Main() cil managed\n    {\n");
+    il.push_str("        .entrypoint\n");
+    il.push_str("        .maxstack 1\n");
+    il.push_str("        call float64 Program::Run()\n");
+    il.push_str("        call void [mscorlib]System.Console::WriteLine(float64)\n");
+    il.push_str("        ret\n    }\n}\n");
+
+    Ok(il)
+}
+
+fn label(offset: usize) -> String {
+    format!("IL_{:04X}", offset)
+}
+
+fn translate(instruction: &Instruction) -> Vec<String> {
+    let lines = |op: &[&str]| op.iter().map(|line| line.to_string()).collect();
+
+    match instruction {
+        Instruction::LDC(value) => vec![format!("ldc.r8 {}", float_literal(*value))],
+        Instruction::LDV(register) => vec![format!("ldloc r{}", register)],
+        Instruction::STV(register) => vec![format!("stloc r{}", register)],
+        Instruction::NEG => vec![String::from("neg")],
+        Instruction::ADD => vec![String::from("add")],
+        Instruction::MUL => vec![String::from("mul")],
+        Instruction::SUB => vec![String::from("sub")],
+        Instruction::DIV => vec![String::from("div")],
+        Instruction::CEQ => vec![String::from("ceq"), String::from("conv.r8")],
+        // cgt pushes 1 when second >= top, which is "not (second < top or unordered)".
+        Instruction::CGT => lines(&["clt.un", "ldc.i4.0", "ceq", "conv.r8"]),
+        Instruction::CLT => lines(&["cgt.un", "ldc.i4.0", "ceq", "conv.r8"]),
+        Instruction::DUP => vec![String::from("dup")],
+        Instruction::POP => vec![String::from("pop")],
+        Instruction::NOP => vec![String::from("nop")],
+        Instruction::RNG => vec![
+            String::from("ldloc random"),
+            String::from("callvirt instance float64 [mscorlib]System.Random::NextDouble()"),
+        ],
+        Instruction::RNGI
+        | Instruction::RNGN
+        | Instruction::RNGE
+        | Instruction::SHUFFLE(_)
+        | Instruction::CHOOSE(_) => unreachable!("rejected by portable()"),
+        Instruction::BR(target) => vec![format!("br {}", label(*target))],
+        Instruction::BRTRUE(target) => vec![
+            String::from("ldc.r8 0.0"),
+            format!("bne.un {}", label(*target)),
+        ],
+        Instruction::BRFALSE(target) => {
+            vec![
+                String::from("ldc.r8 0.0"),
+                format!("beq {}", label(*target)),
+            ]
+        }
+    }
+}
+
+fn float_literal(value: f64) -> String {
+    if value.is_finite() {
+        format!("{:?}", value)
+    } else {
+        format!("float64(0x{:016X})", value.to_bits())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stackalc::{CalcMode, Stackalc};
+
+    fn export_raw(source: &str) -> Result<String, ExportError> {
+        let mut stackalc = Stackalc::default();
+        stackalc.parse(CalcMode::RAW, source).unwrap();
+        export(&stackalc.expr, 2)
+    }
+
+    #[test]
+    fn comparisons_and_branches_are_lowered() {
+        let il = export_raw("ldc:10 ldc:4 sub ldc:3 cgt brtrue:8 ldc:1 pop nop").unwrap();
+
+        assert!(il.contains("        .maxstack 3\n"));
+        assert!(il.contains("        sub\n"));
+        assert!(il.contains("        clt.un\n        ldc.i4.0\n        ceq\n        conv.r8\n"));
+        assert!(il.contains("        ldc.r8 0.0\n        bne.un IL_0008\n"));
+        assert!(il.contains("    IL_0008:\n        // 0x0008  nop\n"));
+        assert!(il.contains("            float64 r0,\n            float64 r1,\n"));
+    }
+
+    #[test]
+    fn programs_the_backend_cannot_translate_are_rejected() {
+        assert!(matches!(
+            export_raw("ldc:1 ldc:6 rngi"),
+            Err(ExportError::Unsupported { offset: 2, .. })
+        ));
+        assert!(matches!(
+            export_raw("ldc:1 stv:5"),
+            Err(ExportError::RegisterOutOfRange {
+                offset: 1,
+                register: 5,
+                registers: 2
+            })
+        ));
+        assert!(matches!(export_raw("add"), Err(ExportError::Unverified(_))));
+    }
+}
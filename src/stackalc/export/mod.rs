@@ -0,0 +1,121 @@
+use crate::stackalc::instructions::Instruction;
+use crate::stackalc::verifier::{Verification, VerifyIssue, verify};
+use std::collections::BTreeSet;
+use std::fmt;
+
+pub mod c;
+pub mod cil;
+pub mod wat;
+pub mod x86_64;
+
+/// Backends translate the stack code statically, so they only accept verified programs whose
+/// registers all exist.
+fn verified(expr: &[Instruction], registers: usize) -> Result<Verification, ExportError> {
+    let verification = verify(expr);
+    if let Some(issue) = verification.issues.first() {
+        return Err(ExportError::Unverified(issue.clone()));
+    }
+
+    let out_of_range =
+        expr.iter()
+            .enumerate()
+            .find_map(|(offset, instruction)| match instruction {
+                Instruction::LDV(register) | Instruction::STV(register)
+                    if *register >= registers =>
+                {
+                    Some((offset, *register))
+                }
+                _ => None,
+            });
+    match out_of_range {
+        Some((offset, register)) => Err(ExportError::RegisterOutOfRange {
+            offset,
+            register,
+            registers,
+        }),
+        None => Ok(verification),
+    }
+}
+
+/// Fails on the first instruction that only the VM and the C backend implement.
+fn portable(expr: &[Instruction]) -> Result<(), ExportError> {
+    let unsupported = expr.iter().enumerate().find(|(_, instruction)| {
+        instruction.is_random() && !matches!(instruction, Instruction::RNG)
+    });
+    match unsupported {
+        Some((offset, instruction)) => Err(ExportError::Unsupported {
+            offset,
+            instruction: instruction.clone(),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Offsets some branch jumps to; the end of the program is included as `expr.len()`.
+fn branch_targets(expr: &[Instruction]) -> BTreeSet<usize> {
+    expr.iter().filter_map(Instruction::branch_target).collect()
+}
+
+/// Registers the program loads or stores, in ascending order.
+fn used_registers(expr: &[Instruction]) -> Vec<usize> {
+    let mut used: Vec<usize> = expr
+        .iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::LDV(register) | Instruction::STV(register) => Some(*register),
+            _ => None,
+        })
+        .collect();
+    used.sort_unstable();
+    used.dedup();
+    used
+}
+
+#[derive(Debug)]
+pub enum ExportError {
+    Unverified(VerifyIssue),
+    RegisterOutOfRange {
+        offset: usize,
+        register: usize,
+        registers: usize,
+    },
+    StackTooDeep {
+        depth: usize,
+        limit: usize,
+    },
+    Unsupported {
+        offset: usize,
+        instruction: Instruction,
+    },
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExportError::Unverified(issue) => write!(f, "Program does not verify: {}", issue),
+            ExportError::RegisterOutOfRange {
+                offset,
+                register,
+                registers,
+            } => write!(
+                f,
+                "Register r{} at {:#06X} is outside the {} registers",
+                register, offset, registers
+            ),
+            ExportError::StackTooDeep { depth, limit } => write!(
+                f,
+                "Stack grows to {} values but only {} fit in registers",
+                depth, limit
+            ),
+            ExportError::Unsupported {
+                offset,
+                instruction,
+            } => write!(
+                f,
+                "{} at {:#06X} has no translation for this target",
+                instruction, offset
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ExportError {}
@@ -0,0 +1,245 @@
+use crate::stackalc::cfg::{Cfg, Successor};
+use crate::stackalc::export::{ExportError, portable, verified};
+use crate::stackalc::instructions::Instruction;
+use std::fmt::Write;
+
+/// Emits a WebAssembly text module exporting `run`, which returns what the VM leaves on top of the
+/// stack or `0` for an empty stack. `rng` calls the host through an `env.random` import.
+///
+/// Straight-line programs map directly onto the wasm operand stack. Programs with branches run
+/// their basic blocks from a dispatch loop: a block spills its stack into `$s` locals before
+/// jumping and the next block reloads them, since values cannot cross wasm block boundaries.
+pub fn export(expr: &[Instruction], registers: usize) -> Result<String, ExportError> {
+    let verification = verified(expr, registers)?;
+    portable(expr)?;
+    let cfg = Cfg::build(expr);
+    let uses_rng = expr
+        .iter()
+        .any(|instruction| matches!(instruction, Instruction::RNG));
+    let has_branches = expr
+        .iter()
+        .any(|instruction| instruction.branch_target().is_some());
+
+    let mut wat = String::from(";; Generated by stackalc\n(module\n");
+    if uses_rng {
+        wat.push_str("  (import \"env\" \"random\" (func $random (result f64)))\n");
+    }
+    wat.push_str("  (func $run (export \"run\") (result f64)\n");
+    for register in 0..registers {
+        let _ = writeln!(wat, "    (local $r{} f64)", register);
+    }
+    wat.push_str("    (local $t0 f64)\n");
+
+    if !has_branches {
+        let mut body = Vec::new();
+        for (offset, instruction) in expr.iter().enumerate() {
+            body.push(format!(";; {:#06X}  {}", offset, instruction));
+            body.extend(translate(instruction));
+        }
+        body.extend(finish(verification.depths[expr.len()].unwrap_or(0)));
+        write_lines(&mut wat, 4, &body);
+        wat.push_str("  )\n)\n");
+        return Ok(wat);
+    }
+
+    for slot in 0..verification.max_depth() {
+        let _ = writeln!(wat, "    (local $s{} f64)", slot);
+    }
+    wat.push_str("    (local $pc i32)\n");
+
+    // One extra dispatch target after the real blocks collects the result.
+    let exit = cfg.blocks.len();
+    let index = |successor: Successor| match successor {
+        Successor::Block(block) => block,
+        Successor::Exit => exit,
+    };
+
+    wat.push_str("    loop $dispatch\n");
+    for block in (0..=exit).rev() {
+        let _ = writeln!(wat, "{}block $B{}", indent(3 + exit - block), block);
+    }
+    let labels: Vec<String> = (0..=exit).map(|block| format!("$B{}", block)).collect();
+    let _ = writeln!(wat, "{}local.get $pc", indent(4 + exit));
+    let _ = writeln!(wat, "{}br_table {}", indent(4 + exit), labels.join(" "));
+
+    for block in 0..=exit {
+        let depth = 3 + exit - block;
+        let _ = writeln!(wat, "{}end", indent(depth));
+
+        let mut body = Vec::new();
+        if block == exit {
+            body.push(String::from(";; exit"));
+            let final_depth = verification.depths[expr.len()].unwrap_or(0);
+            body.extend(reload(final_depth));
+            body.extend(finish(final_depth));
+            body.push(String::from("return"));
+            write_lines(&mut wat, depth, &body);
+            continue;
+        }
+
+        let basic_block = &cfg.blocks[block];
+        body.push(format!(";; B{}", block));
+        let Some(entry_depth) = verification.depths[basic_block.start] else {
+            body.push(String::from("unreachable"));
+            write_lines(&mut wat, depth, &body);
+            continue;
+        };
+        body.extend(reload(entry_depth));
+
+        let instructions = &expr[basic_block.start..basic_block.end];
+        for (offset, instruction) in (basic_block.start..).zip(instructions) {
+            body.push(format!(";; {:#06X}  {}", offset, instruction));
+            if instruction.branch_target().is_none() {
+                body.extend(translate(instruction));
+            }
+        }
+
+        let last = &expr[basic_block.end - 1];
+        let (pops, pushes) = last.stack_effect();
+        let exit_depth = verification.depths[basic_block.end - 1].unwrap_or(0) - pops + pushes;
+        match last {
+            Instruction::BRTRUE(_) | Instruction::BRFALSE(_) => {
+                let comparison = match last {
+                    Instruction::BRTRUE(_) => "f64.ne",
+                    _ => "f64.eq",
+                };
+                body.push(String::from("local.set $t0"));
+                body.extend(spill(exit_depth));
+                let [taken, fallthrough] = [0, 1].map(|i| index(basic_block.successors[i].target));
+                body.push(format!("i32.const {}", taken));
+                body.push(format!("i32.const {}", fallthrough));
+                body.push(String::from("local.get $t0"));
+                body.push(String::from("f64.const 0"));
+                body.push(String::from(comparison));
+                body.push(String::from("select"));
+            }
+            _ => {
+                body.extend(spill(exit_depth));
+                body.push(format!(
+                    "i32.const {}",
+                    index(basic_block.successors[0].target)
+                ));
+            }
+        }
+        body.push(String::from("local.set $pc"));
+        body.push(String::from("br $dispatch"));
+        write_lines(&mut wat, depth, &body);
+    }
+
+    wat.push_str("    end\n    unreachable\n  )\n)\n");
+    Ok(wat)
+}
+
+fn translate(instruction: &Instruction) -> Vec<String> {
+    let line = |line: &str| vec![String::from(line)];
+
+    match instruction {
+        Instruction::LDC(value) => vec![format!("f64.const {}", float_literal(*value))],
+        Instruction::LDV(register) => vec![format!("local.get $r{}", register)],
+        Instruction::STV(register) => vec![format!("local.set $r{}", register)],
+        Instruction::NEG => line("f64.neg"),
+        Instruction::ADD => line("f64.add"),
+        Instruction::MUL => line("f64.mul"),
+        Instruction::SUB => line("f64.sub"),
+        Instruction::DIV => line("f64.div"),
+        Instruction::CEQ => vec![String::from("f64.eq"), String::from("f64.convert_i32_u")],
+        Instruction::CGT => vec![String::from("f64.ge"), String::from("f64.convert_i32_u")],
+        Instruction::CLT => vec![String::from("f64.le"), String::from("f64.convert_i32_u")],
+        Instruction::DUP => vec![String::from("local.tee $t0"), String::from("local.get $t0")],
+        Instruction::POP => line("drop"),
+        Instruction::NOP => line("nop"),
+        Instruction::RNG => line("call $random"),
+        Instruction::RNGI
+        | Instruction::RNGN
+        | Instruction::RNGE
+        | Instruction::SHUFFLE(_)
+        | Instruction::CHOOSE(_) => unreachable!("rejected by portable()"),
+        Instruction::BR(_) | Instruction::BRTRUE(_) | Instruction::BRFALSE(_) => Vec::new(),
+    }
+}
+
+/// Leaves only the top of a stack `depth` values deep, or `0` when it is empty.
+fn finish(depth: usize) -> Vec<String> {
+    match depth {
+        0 => vec![String::from("f64.const 0")],
+        1 => Vec::new(),
+        depth => {
+            let mut lines = vec![String::from("local.set $t0")];
+            lines.extend((1..depth).map(|_| String::from("drop")));
+            lines.push(String::from("local.get $t0"));
+            lines
+        }
+    }
+}
+
+fn spill(depth: usize) -> Vec<String> {
+    (0..depth)
+        .rev()
+        .map(|slot| format!("local.set $s{}", slot))
+        .collect()
+}
+
+fn reload(depth: usize) -> Vec<String> {
+    (0..depth)
+        .map(|slot| format!("local.get $s{}", slot))
+        .collect()
+}
+
+fn indent(depth: usize) -> String {
+    "  ".repeat(depth)
+}
+
+fn write_lines(wat: &mut String, depth: usize, lines: &[String]) {
+    for line in lines {
+        let _ = writeln!(wat, "{}{}", indent(depth), line);
+    }
+}
+
+fn float_literal(value: f64) -> String {
+    if value.is_nan() {
+        String::from("nan")
+    } else {
+        format!("{:?}", value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stackalc::{CalcMode, Stackalc};
+
+    /// The exported instructions without comments or indentation, one per line.
+    fn export_raw(source: &str) -> String {
+        let mut stackalc = Stackalc::default();
+        stackalc.parse(CalcMode::RAW, source).unwrap();
+        export(&stackalc.expr, 1)
+            .unwrap()
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.starts_with(";;"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn straight_line_code_uses_the_operand_stack() {
+        let wat = export_raw("ldc:10 ldc:4 sub ldc:NaN ldc:2 clt");
+
+        assert!(wat.contains("f64.const 10.0\nf64.const 4.0\nf64.sub\nf64.const nan\n"));
+        assert!(wat.contains("f64.le\nf64.convert_i32_u\nlocal.set $t0\ndrop\nlocal.get $t0\n)"));
+        assert!(!wat.contains("$dispatch"));
+    }
+
+    #[test]
+    fn branches_run_from_a_dispatch_loop() {
+        let wat = export_raw("ldc:1 ldc:0 brfalse:5 ldc:2 add stv:0");
+
+        assert!(wat.contains("(local $s0 f64)\n(local $s1 f64)\n(local $pc i32)\n"));
+        assert!(wat.contains("local.get $pc\nbr_table $B0 $B1 $B2 $B3\n"));
+        // B0 spills the 1 below the condition and goes to B2 when the condition is 0, else B1.
+        assert!(wat.contains("local.set $t0\nlocal.set $s0\ni32.const 2\ni32.const 1\n"));
+        assert!(wat.contains("local.get $t0\nf64.const 0\nf64.eq\nselect\nlocal.set $pc\n"));
+        // B1 reloads it before adding.
+        assert!(wat.contains("end\nlocal.get $s0\nf64.const 2.0\nf64.add\n"));
+    }
+}
@@ -0,0 +1,233 @@
+use crate::stackalc::export::{ExportError, branch_targets, portable, used_registers, verified};
+use crate::stackalc::instructions::Instruction;
+use std::fmt::Write;
+
+/// Stack slots live in `xmm0`..`xmm14`; `xmm15` is kept free as a scratch register.
+const STACK_REGISTERS: usize = 15;
+
+/// Emits GNU assembler source in Intel syntax for a System V x86-64 function
+/// `double stackalc_run(void)`. Stack slot `n` is kept in `xmmn`, so the position of every value
+/// is known statically from the verified depths, and registers live in the stack frame. `rng`
+/// calls an external `double stackalc_random(void)`.
+pub fn export(expr: &[Instruction], registers: usize) -> Result<String, ExportError> {
+    let verification = verified(expr, registers)?;
+    portable(expr)?;
+    let max_depth = verification.max_depth();
+    if max_depth > STACK_REGISTERS {
+        return Err(ExportError::StackTooDeep {
+            depth: max_depth,
+            limit: STACK_REGISTERS,
+        });
+    }
+
+    let targets = branch_targets(expr);
+    let used = used_registers(expr);
+    let uses_rng = expr
+        .iter()
+        .any(|instruction| matches!(instruction, Instruction::RNG));
+
+    // The frame holds the registers and, around calls, the live stack slots.
+    let spill_slots = if uses_rng { max_depth } else { 0 };
+    let frame = (8 * (used.len() + spill_slots)).next_multiple_of(16);
+    let mut lowering = Lowering {
+        used,
+        constants: Vec::new(),
+    };
+
+    let mut asm = String::from("# Generated by stackalc\n");
+    asm.push_str("    .intel_syntax noprefix\n    .text\n    .globl stackalc_run\n");
+    asm.push_str("stackalc_run:\n    push rbp\n    mov rbp, rsp\n");
+    if frame > 0 {
+        let _ = writeln!(asm, "    sub rsp, {}", frame);
+    }
+    if !lowering.used.is_empty() {
+        asm.push_str("    pxor xmm15, xmm15\n");
+        for register in lowering.used.clone() {
+            let _ = writeln!(asm, "    movsd {}, xmm15", lowering.register(register));
+        }
+    }
+
+    for (offset, instruction) in expr.iter().enumerate() {
+        if targets.contains(&offset) {
+            let _ = writeln!(asm, "{}:", label(offset));
+        }
+        let _ = writeln!(asm, "    # {:#06X}  {}", offset, instruction);
+        if let Some(depth) = verification.depths[offset] {
+            for line in lowering.lower(instruction, depth) {
+                let _ = writeln!(asm, "    {}", line);
+            }
+        }
+    }
+
+    if targets.contains(&expr.len()) {
+        let _ = writeln!(asm, "{}:", label(expr.len()));
+    }
+    match verification.depths[expr.len()].unwrap_or(0) {
+        0 => asm.push_str("    pxor xmm0, xmm0\n"),
+        1 => {}
+        depth => {
+            let _ = writeln!(asm, "    movapd xmm0, xmm{}", depth - 1);
+        }
+    }
+    asm.push_str("    leave\n    ret\n");
+
+    // Packed operands have to be 16-byte aligned, so every constant takes a full xmm width.
+    asm.push_str("\n    .section .rodata\n    .align 16\n");
+    asm.push_str(".LC_SIGN:\n    .quad 0x8000000000000000, 0\n");
+    asm.push_str(".LC_ONE:\n    .quad 0x3FF0000000000000, 0\n");
+    for (index, bits) in lowering.constants.iter().enumerate() {
+        let _ = writeln!(asm, ".LC{}:\n    .quad {:#018X}, 0", index, bits);
+    }
+    asm.push_str("    .section .note.GNU-stack,\"\",@progbits\n");
+
+    Ok(asm)
+}
+
+fn label(offset: usize) -> String {
+    format!(".L{:04X}", offset)
+}
+
+struct Lowering {
+    used: Vec<usize>,
+    constants: Vec<u64>,
+}
+
+impl Lowering {
+    fn register(&self, register: usize) -> String {
+        // `used` lists every register the program touches, and `verified` has already rejected
+        // any outside the register file, so each one has a slot.
+        let index = self
+            .used
+            .binary_search(&register)
+            .expect("every register the program uses has a frame slot");
+        format!("qword ptr [rbp - {}]", 8 * (index + 1))
+    }
+
+    fn spill_slot(&self, slot: usize) -> String {
+        format!("qword ptr [rbp - {}]", 8 * (self.used.len() + slot + 1))
+    }
+
+    fn constant(&mut self, value: f64) -> String {
+        let bits = value.to_bits();
+        let index = match self.constants.iter().position(|&known| known == bits) {
+            Some(index) => index,
+            None => {
+                self.constants.push(bits);
+                self.constants.len() - 1
+            }
+        };
+        format!("qword ptr [rip + .LC{}]", index)
+    }
+
+    /// `depth` is the number of values on the stack before the instruction runs, so the top of
+    /// the stack is `xmm{depth - 1}` and the value below it `xmm{depth - 2}`.
+    fn lower(&mut self, instruction: &Instruction, depth: usize) -> Vec<String> {
+        let top = || format!("xmm{}", depth.saturating_sub(1));
+        let second = || format!("xmm{}", depth.saturating_sub(2));
+        let next = format!("xmm{}", depth);
+
+        // Scalar compares leave an all-ones or all-zero mask, which `.LC_ONE` turns into 1 or 0.
+        let compare = |op: &str, lhs: String, rhs: String| {
+            vec![
+                format!("movapd xmm15, {}", lhs),
+                format!("{} xmm15, {}", op, rhs),
+                String::from("andpd xmm15, xmmword ptr [rip + .LC_ONE]"),
+                format!("movapd {}, xmm15", second()),
+            ]
+        };
+
+        match instruction {
+            Instruction::LDC(value) => vec![format!("movsd {}, {}", next, self.constant(*value))],
+            Instruction::LDV(register) => {
+                vec![format!("movsd {}, {}", next, self.register(*register))]
+            }
+            Instruction::STV(register) => {
+                vec![format!("movsd {}, {}", self.register(*register), top())]
+            }
+            Instruction::NEG => vec![format!("xorpd {}, xmmword ptr [rip + .LC_SIGN]", top())],
+            Instruction::ADD => vec![format!("addsd {}, {}", second(), top())],
+            Instruction::MUL => vec![format!("mulsd {}, {}", second(), top())],
+            Instruction::SUB => vec![format!("subsd {}, {}", second(), top())],
+            Instruction::DIV => vec![format!("divsd {}, {}", second(), top())],
+            Instruction::CEQ => compare("cmpeqsd", top(), second()),
+            // second >= top is tested as top <= second, which is false for NaN like in the VM.
+            Instruction::CGT => compare("cmplesd", top(), second()),
+            Instruction::CLT => compare("cmplesd", second(), top()),
+            Instruction::DUP => vec![format!("movapd {}, {}", next, top())],
+            Instruction::POP | Instruction::NOP => Vec::new(),
+            Instruction::RNG => {
+                // Every xmm register is caller-saved, so the live slots go to the frame first.
+                let mut lines: Vec<String> = (0..depth)
+                    .map(|slot| format!("movsd {}, xmm{}", self.spill_slot(slot), slot))
+                    .collect();
+                lines.push(String::from("call stackalc_random"));
+                if depth > 0 {
+                    lines.push(format!("movapd {}, xmm0", next));
+                }
+                lines.extend(
+                    (0..depth).map(|slot| format!("movsd xmm{}, {}", slot, self.spill_slot(slot))),
+                );
+                lines
+            }
+            Instruction::RNGI
+            | Instruction::RNGN
+            | Instruction::RNGE
+            | Instruction::SHUFFLE(_)
+            | Instruction::CHOOSE(_) => unreachable!("rejected by portable()"),
+            Instruction::BR(target) => vec![format!("jmp {}", label(*target))],
+            // An unordered compare (NaN) sets the parity flag; NaN counts as non-zero.
+            Instruction::BRTRUE(target) => vec![
+                String::from("pxor xmm15, xmm15"),
+                format!("ucomisd {}, xmm15", top()),
+                format!("jne {}", label(*target)),
+                format!("jp {}", label(*target)),
+            ],
+            Instruction::BRFALSE(target) => vec![
+                String::from("pxor xmm15, xmm15"),
+                format!("ucomisd {}, xmm15", top()),
+                String::from("jp 1f"),
+                format!("je {}", label(*target)),
+                String::from("1:"),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stackalc::{CalcMode, Stackalc};
+
+    fn export_raw(source: &str) -> Result<String, ExportError> {
+        let mut stackalc = Stackalc::default();
+        stackalc.parse(CalcMode::RAW, source).unwrap();
+        export(&stackalc.expr, 8)
+    }
+
+    #[test]
+    fn stack_slots_live_in_xmm_registers() {
+        let asm = export_raw("ldc:10 ldc:4 sub stv:5 ldv:5 ldc:10 cgt").unwrap();
+
+        assert!(asm.contains("    sub rsp, 16\n    pxor xmm15, xmm15\n"));
+        assert!(asm.contains("    movsd xmm1, qword ptr [rip + .LC1]\n    # 0x0002  sub\n"));
+        assert!(asm.contains("    subsd xmm0, xmm1\n"));
+        assert!(asm.contains("    movsd qword ptr [rbp - 8], xmm0\n"));
+        assert!(asm.contains("    movapd xmm15, xmm1\n    cmplesd xmm15, xmm0\n"));
+        assert!(asm.contains("    movsd xmm1, qword ptr [rip + .LC0]\n    # 0x0006  cgt\n"));
+        assert!(asm.contains(".LC1:\n    .quad 0x4010000000000000, 0\n"));
+        assert!(!asm.contains(".LC2:"));
+    }
+
+    #[test]
+    fn deep_stacks_are_rejected() {
+        let source = vec!["ldc:1"; STACK_REGISTERS + 1].join(" ");
+
+        assert!(matches!(
+            export_raw(&source),
+            Err(ExportError::StackTooDeep {
+                depth: 16,
+                limit: 15
+            })
+        ));
+    }
+}
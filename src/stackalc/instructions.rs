@@ -1,6 +1,7 @@
 use std::fmt::Display;
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Instruction {
     LDC(f64),
     LDV(usize),
@@ -17,22 +18,110 @@ pub enum Instruction {
     POP,
     NOP,
     RNG,
+    RNGI,
+    RNGN,
+    RNGE,
+    SHUFFLE(usize),
+    CHOOSE(usize),
     BR(usize),
     BRTRUE(usize),
     BRFALSE(usize),
 }
 
+impl Instruction {
+    /// Number of values the instruction pops and pushes when it executes normally.
+    pub fn stack_effect(&self) -> (usize, usize) {
+        match self {
+            Instruction::LDC(_)
+            | Instruction::LDV(_)
+            | Instruction::RNG
+            | Instruction::RNGN
+            | Instruction::RNGE => (0, 1),
+            Instruction::STV(_) | Instruction::POP => (1, 0),
+            Instruction::NEG => (1, 1),
+            Instruction::DUP => (1, 2),
+            Instruction::ADD
+            | Instruction::MUL
+            | Instruction::SUB
+            | Instruction::DIV
+            | Instruction::CEQ
+            | Instruction::CGT
+            | Instruction::CLT
+            | Instruction::RNGI => (2, 1),
+            Instruction::SHUFFLE(n) => (*n, *n),
+            // Choosing from nothing leaves nothing.
+            Instruction::CHOOSE(n) => (*n, (*n).min(1)),
+            Instruction::NOP | Instruction::BR(_) => (0, 0),
+            Instruction::BRTRUE(_) | Instruction::BRFALSE(_) => (1, 0),
+        }
+    }
+
+    /// Whether the instruction draws from the random number generator, so two executions of it
+    /// can give different results.
+    pub fn is_random(&self) -> bool {
+        matches!(
+            self,
+            Instruction::RNG
+                | Instruction::RNGI
+                | Instruction::RNGN
+                | Instruction::RNGE
+                | Instruction::SHUFFLE(_)
+                | Instruction::CHOOSE(_)
+        )
+    }
+
+    pub fn branch_target(&self) -> Option<usize> {
+        match self {
+            Instruction::BR(target)
+            | Instruction::BRTRUE(target)
+            | Instruction::BRFALSE(target) => Some(*target),
+            _ => None,
+        }
+    }
+
+    /// Offsets execution can continue at, where `len` stands for the end of the program: the next
+    /// offset, then the target of a conditional branch. The VM ignores branches past `len`, so
+    /// those only fall through.
+    pub fn successors(&self, offset: usize, len: usize) -> Vec<usize> {
+        match self {
+            Instruction::BR(target) if *target <= len => vec![*target],
+            Instruction::BRTRUE(target) | Instruction::BRFALSE(target) if *target <= len => {
+                vec![offset + 1, *target]
+            }
+            _ => vec![offset + 1],
+        }
+    }
+
+    pub fn retarget(&mut self, new_target: usize) {
+        if let Instruction::BR(target)
+        | Instruction::BRTRUE(target)
+        | Instruction::BRFALSE(target) = self
+        {
+            *target = new_target;
+        }
+    }
+
+    pub fn relocate(&mut self, base: usize) {
+        if let Instruction::BR(target)
+        | Instruction::BRTRUE(target)
+        | Instruction::BRFALSE(target) = self
+        {
+            *target += base;
+        }
+    }
+}
+
 impl Display for Instruction {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let instruction = match self {
             Instruction::LDC(value) => {
-                format!("ldc:{}", value.to_string())
+                format!("ldc:{}", value)
             }
             Instruction::LDV(value) => {
-                format!("ldv:{}", value.to_string())
+                format!("ldv:{}", value)
             }
             Instruction::STV(value) => {
-                format!("stv:{}", value.to_string())
+                format!("stv:{}", value)
             }
             Instruction::NEG => String::from("neg"),
             Instruction::ADD => String::from("add"),
@@ -46,14 +135,19 @@ impl Display for Instruction {
             Instruction::POP => String::from("pop"),
             Instruction::NOP => String::from("nop"),
             Instruction::RNG => String::from("rng"),
+            Instruction::RNGI => String::from("rngi"),
+            Instruction::RNGN => String::from("rngn"),
+            Instruction::RNGE => String::from("rnge"),
+            Instruction::SHUFFLE(n) => format!("shuffle:{}", n),
+            Instruction::CHOOSE(n) => format!("choose:{}", n),
             Instruction::BR(value) => {
-                format!("br:{}", value.to_string())
+                format!("br:{}", value)
             }
             Instruction::BRTRUE(value) => {
-                format!("brtrue:{}", value.to_string())
+                format!("brtrue:{}", value)
             }
             Instruction::BRFALSE(value) => {
-                format!("brfalse:{}", value.to_string())
+                format!("brfalse:{}", value)
             }
         };
         write!(f, "{}", instruction)
@@ -0,0 +1,119 @@
+use std::fmt;
+
+/// Just enough JSON to write traces and program state; `Display` renders it on one line.
+#[derive(Clone, Debug)]
+pub enum Json {
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object<const N: usize>(fields: [(&str, Json); N]) -> Json {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    pub fn numbers(values: &[f64]) -> Json {
+        Json::Array(values.iter().copied().map(Json::Number).collect())
+    }
+
+    /// Registers that hold a value, keyed by name, so `r0 = 5` becomes `{"r0": 5}`.
+    pub fn registers<'a>(registers: impl IntoIterator<Item = (usize, &'a f64)>) -> Json {
+        Json::Object(
+            registers
+                .into_iter()
+                .map(|(register, value)| (format!("r{}", register), Json::Number(*value)))
+                .collect(),
+        )
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            // JSON has no NaN or infinities, so those are written as strings.
+            Json::Number(value) if value.is_nan() => write!(f, "\"NaN\""),
+            Json::Number(value) if value.is_infinite() => {
+                let sign = if *value < 0.0 { "-" } else { "" };
+                write!(f, "\"{}Infinity\"", sign)
+            }
+            Json::Number(value) => write!(f, "{}", value),
+            Json::String(value) => write_string(f, value),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, value: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strings_are_escaped() {
+        let json = Json::String(String::from("say \"hi\"\\\n\tnow\u{1}"));
+
+        assert_eq!(json.to_string(), r#""say \"hi\"\\\n\tnow\u0001""#);
+    }
+
+    #[test]
+    fn non_finite_numbers_become_strings() {
+        let json = Json::numbers(&[1.5, -0.0, f64::NAN, f64::INFINITY, f64::NEG_INFINITY]);
+
+        assert_eq!(json.to_string(), r#"[1.5,-0,"NaN","Infinity","-Infinity"]"#);
+    }
+
+    #[test]
+    fn objects_keep_their_field_order() {
+        let json = Json::object([
+            ("stack", Json::numbers(&[])),
+            ("registers", Json::registers([(3, &2.0), (0, &1.0)])),
+        ]);
+
+        assert_eq!(
+            json.to_string(),
+            r#"{"stack":[],"registers":{"r3":2,"r0":1}}"#
+        );
+    }
+}
@@ -0,0 +1,176 @@
+use crate::stackalc::instructions::Instruction;
+use std::collections::HashSet;
+use std::fmt;
+
+#[derive(Clone, Debug)]
+pub struct Liveness {
+    pub reachable: Vec<bool>,
+    pub warnings: Vec<LivenessWarning>,
+}
+
+impl Liveness {
+    pub fn warnings_at(&self, offset: usize) -> impl Iterator<Item = &LivenessWarning> {
+        self.warnings
+            .iter()
+            .filter(move |warning| warning.offset() == offset)
+    }
+}
+
+pub fn analyze(expr: &[Instruction]) -> Liveness {
+    let len = expr.len();
+    let successors: Vec<Vec<usize>> = expr
+        .iter()
+        .enumerate()
+        .map(|(offset, instruction)| instruction.successors(offset, len))
+        .collect();
+
+    let mut reachable = vec![false; len];
+    let mut worklist = vec![0];
+    while let Some(offset) = worklist.pop() {
+        if offset < len && !reachable[offset] {
+            reachable[offset] = true;
+            worklist.extend(&successors[offset]);
+        }
+    }
+
+    // Forward: registers some path may have written before each offset.
+    let mut written_in: Vec<HashSet<usize>> = vec![HashSet::new(); len + 1];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for offset in (0..len).filter(|&offset| reachable[offset]) {
+            let mut written_out = written_in[offset].clone();
+            if let Instruction::STV(register) = expr[offset] {
+                written_out.insert(register);
+            }
+            for &successor in &successors[offset] {
+                let before = written_in[successor].len();
+                written_in[successor].extend(&written_out);
+                changed |= written_in[successor].len() != before;
+            }
+        }
+    }
+
+    // Backward: registers some path may still read after each offset.
+    let mut live_out: Vec<HashSet<usize>> = vec![HashSet::new(); len];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for offset in (0..len).rev().filter(|&offset| reachable[offset]) {
+            let mut live = HashSet::new();
+            for &successor in successors[offset].iter().filter(|&&s| s < len) {
+                live.extend(live_in(&expr[successor], &live_out[successor]));
+            }
+            if live != live_out[offset] {
+                live_out[offset] = live;
+                changed = true;
+            }
+        }
+    }
+
+    let mut warnings = Vec::new();
+    for offset in (0..len).filter(|&offset| reachable[offset]) {
+        match expr[offset] {
+            Instruction::STV(register) if !live_out[offset].contains(&register) => {
+                warnings.push(LivenessWarning::DeadStore { offset, register });
+            }
+            Instruction::LDV(register) if !written_in[offset].contains(&register) => {
+                warnings.push(LivenessWarning::UnwrittenRead { offset, register });
+            }
+            _ => {}
+        }
+    }
+
+    Liveness {
+        reachable,
+        warnings,
+    }
+}
+
+fn live_in(instruction: &Instruction, live_out: &HashSet<usize>) -> HashSet<usize> {
+    let mut live = live_out.clone();
+    match instruction {
+        Instruction::STV(register) => {
+            live.remove(register);
+        }
+        Instruction::LDV(register) => {
+            live.insert(*register);
+        }
+        _ => {}
+    }
+    live
+}
+
+#[derive(Clone, Debug)]
+pub enum LivenessWarning {
+    DeadStore { offset: usize, register: usize },
+    UnwrittenRead { offset: usize, register: usize },
+}
+
+impl LivenessWarning {
+    pub fn offset(&self) -> usize {
+        match self {
+            LivenessWarning::DeadStore { offset, .. }
+            | LivenessWarning::UnwrittenRead { offset, .. } => *offset,
+        }
+    }
+}
+
+impl fmt::Display for LivenessWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LivenessWarning::DeadStore { offset, register } => write!(
+                f,
+                "Value stored to r{} at {:#06X} is never read",
+                register, offset
+            ),
+            LivenessWarning::UnwrittenRead { offset, register } => write!(
+                f,
+                "r{} is read at {:#06X} but never written",
+                register, offset
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stackalc::{CalcMode, Stackalc};
+
+    fn analyze_raw(source: &str) -> Liveness {
+        let mut stackalc = Stackalc::default();
+        stackalc.parse(CalcMode::RAW, source).unwrap();
+        analyze(&stackalc.expr)
+    }
+
+    #[test]
+    fn overwritten_stores_and_unwritten_reads_are_reported() {
+        let liveness = analyze_raw("ldc:1 stv:0 ldc:2 stv:0 ldv:0 ldv:1 br:9 ldc:5 ldc:6");
+
+        assert_eq!(
+            liveness.reachable,
+            [true, true, true, true, true, true, true, false, false]
+        );
+        assert!(matches!(
+            liveness.warnings.as_slice(),
+            [
+                LivenessWarning::DeadStore {
+                    offset: 1,
+                    register: 0
+                },
+                LivenessWarning::UnwrittenRead {
+                    offset: 5,
+                    register: 1
+                },
+            ]
+        ));
+    }
+
+    #[test]
+    fn stores_read_around_a_loop_are_live() {
+        let liveness = analyze_raw("ldc:0 stv:0 ldv:0 ldc:1 add dup stv:0 brtrue:2");
+
+        assert!(liveness.warnings.is_empty(), "{:?}", liveness.warnings);
+    }
+}
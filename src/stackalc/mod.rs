@@ -1,39 +1,109 @@
+use crate::stackalc::decompiler::{DecompileError, Decompiled};
 use crate::stackalc::instructions::Instruction;
+use crate::stackalc::random::{SplitMix64, random_seed};
+use crate::stackalc::registers::DEFAULT_REGISTERS;
+use crate::stackalc::session::Session;
+use crate::stackalc::trace::TraceEntry;
 use rand::Rng;
+use rand::seq::SliceRandom;
 use ratatui::prelude::*;
 use ratatui::widgets::ListState;
-
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+pub mod ast;
+pub mod bytecode;
+pub mod cfg;
+pub mod codegen;
+pub mod decompiler;
+pub mod export;
 pub mod instructions;
-mod parser;
+pub mod json;
+pub mod liveness;
+pub mod optimizer;
+pub mod parser;
+pub mod program_file;
+pub mod random;
+pub mod registers;
+pub mod runtime;
+pub mod session;
+pub mod snapshot;
+pub mod tac;
+pub mod trace;
+pub mod verifier;
 
 #[derive(Clone)]
 pub struct Stackalc {
     pub stack: Vec<f64>,
-    pub memory: [Option<f64>; 32],
+    pub memory: Vec<Option<f64>>,
+    pub aliases: BTreeMap<String, usize>,
     pub expr: Vec<Instruction>,
+    pub spans: Vec<Option<Range<usize>>>,
     pub instruction_list_state: ListState,
     pub stack_list_state: ListState,
     pub calc_mode: CalcMode,
+    pub cse: bool,
+    pub reorder: bool,
     pub input_mode: InputMode,
     pub calculator_query: String,
     pub calculator_query_char_idx: usize,
+    pub command_query: String,
+    pub panel: Option<Panel>,
+    pub program_path: Option<PathBuf>,
     pub cursor_position: Option<Position>,
+    pub decompiled: Option<Result<Decompiled, DecompileError>>,
+    pub status: Option<String>,
+    pub trace: Option<Vec<TraceEntry>>,
+    pub trace_list_state: ListState,
+    pub pending_session: Option<Session>,
+    pub seed: u64,
+    pub rng: SplitMix64,
+    pub history: Vec<Checkpoint>,
     pub exit: bool,
 }
 
+/// The VM as it was before a step, so the step can be undone.
+#[derive(Clone)]
+pub struct Checkpoint {
+    pub selected: Option<usize>,
+    pub stack: Vec<f64>,
+    pub memory: Vec<Option<f64>>,
+    pub rng: SplitMix64,
+    pub trace_len: usize,
+}
+
 impl Default for Stackalc {
     fn default() -> Self {
+        let seed = random_seed();
         Self {
             stack: Vec::new(),
-            memory: [None; 32],
+            memory: vec![None; DEFAULT_REGISTERS],
+            aliases: BTreeMap::new(),
             expr: Vec::new(),
+            spans: Vec::new(),
             instruction_list_state: ListState::default(),
             stack_list_state: ListState::default(),
             calc_mode: CalcMode::INFIX,
+            cse: false,
+            reorder: false,
             input_mode: InputMode::Normal,
             calculator_query: String::new(),
             calculator_query_char_idx: 0,
+            command_query: String::new(),
+            panel: None,
+            program_path: None,
             cursor_position: None,
+            decompiled: None,
+            status: None,
+            trace: None,
+            trace_list_state: ListState::default(),
+            pending_session: None,
+            seed,
+            rng: SplitMix64::new(seed),
+            history: Vec::new(),
             exit: false,
         }
     }
@@ -42,8 +112,25 @@ impl Default for Stackalc {
 impl Stackalc {
     pub fn execute_selected(&mut self) {
         let instruction_idx = self.instruction_list_state.selected().unwrap();
-        if let Some(instruction) = self.expr.get(instruction_idx).cloned() {
-            self.execute_instruction(instruction);
+        let Some(instruction) = self.expr.get(instruction_idx).cloned() else {
+            return;
+        };
+        let stack = self.stack.clone();
+
+        // A taken branch goes on to execute its target, so it is recorded before jumping.
+        let target = match instruction {
+            Instruction::BR(n) => Some(n),
+            Instruction::BRTRUE(n) => self.stack.pop().filter(|value| *value != 0.0).map(|_| n),
+            Instruction::BRFALSE(n) => self.stack.pop().filter(|value| *value == 0.0).map(|_| n),
+            ref instruction => {
+                self.execute_instruction(instruction.clone());
+                None
+            }
+        };
+        self.record_step(instruction_idx, instruction, stack);
+
+        if let Some(n) = target {
+            self.br(n);
         }
     }
 
@@ -64,6 +151,11 @@ impl Stackalc {
             Instruction::POP => self.pop(),
             Instruction::NOP => self.nop(),
             Instruction::RNG => self.rng(),
+            Instruction::RNGI => self.rngi(),
+            Instruction::RNGN => self.rngn(),
+            Instruction::RNGE => self.rnge(),
+            Instruction::SHUFFLE(n) => self.shuffle(n),
+            Instruction::CHOOSE(n) => self.choose(n),
             Instruction::BR(n) => self.br(n),
             Instruction::BRTRUE(n) => self.brtrue(n),
             Instruction::BRFALSE(n) => self.brfalse(n),
@@ -75,18 +167,16 @@ impl Stackalc {
     }
 
     pub fn ldv(&mut self, n: usize) {
-        if n <= self.memory.len() {
-            if let Some(value) = self.memory[n] {
-                self.stack.push(value);
-            }
+        if let Some(Some(value)) = self.memory.get(n).copied() {
+            self.stack.push(value);
         }
     }
 
     pub fn stv(&mut self, n: usize) {
-        if n <= self.memory.len() {
-            if let Some(value) = self.stack.pop() {
-                self.memory[n] = Some(value)
-            }
+        if n < self.memory.len()
+            && let Some(value) = self.stack.pop()
+        {
+            self.memory[n] = Some(value)
         }
     }
 
@@ -121,7 +211,7 @@ impl Stackalc {
             let first_value = self.stack.pop().unwrap();
             let second_value = self.stack.pop().unwrap();
 
-            let result = first_value - second_value;
+            let result = second_value - first_value;
             self.stack.push(result);
         }
     }
@@ -131,7 +221,7 @@ impl Stackalc {
             let first_value = self.stack.pop().unwrap();
             let second_value = self.stack.pop().unwrap();
 
-            let result = first_value / second_value;
+            let result = second_value / first_value;
             self.stack.push(result);
         }
     }
@@ -154,7 +244,7 @@ impl Stackalc {
         if self.stack.len() >= 2 {
             let first_value = self.stack.pop().unwrap();
             let second_value = self.stack.pop().unwrap();
-            let gt = first_value >= second_value;
+            let gt = second_value >= first_value;
 
             if gt {
                 self.stack.push(1.0);
@@ -168,7 +258,7 @@ impl Stackalc {
         if self.stack.len() >= 2 {
             let first_value = self.stack.pop().unwrap();
             let second_value = self.stack.pop().unwrap();
-            let lt = first_value <= second_value;
+            let lt = second_value <= first_value;
 
             if lt {
                 self.stack.push(1.0);
@@ -180,7 +270,7 @@ impl Stackalc {
 
     pub fn dup(&mut self) {
         if let Some(top) = self.stack.last() {
-            self.stack.push(top.clone());
+            self.stack.push(*top);
         }
     }
 
@@ -196,18 +286,20 @@ impl Stackalc {
     }
 
     pub fn brtrue(&mut self, n: usize) {
-        if let Some(value) = self.stack.pop() {
-            if n <= self.expr.len() && value != 0.0 {
-                self.br(n)
-            }
+        if let Some(value) = self.stack.pop()
+            && n <= self.expr.len()
+            && value != 0.0
+        {
+            self.br(n)
         }
     }
 
     pub fn brfalse(&mut self, n: usize) {
-        if let Some(value) = self.stack.pop() {
-            if n <= self.expr.len() && value == 0.0 {
-                self.br(n)
-            }
+        if let Some(value) = self.stack.pop()
+            && n <= self.expr.len()
+            && value == 0.0
+        {
+            self.br(n)
         }
     }
 
@@ -216,29 +308,151 @@ impl Stackalc {
     }
 
     pub fn rng(&mut self) {
-        let mut rng = rand::thread_rng();
-        let n = rng.random_range(0.0..1.0);
+        let n = self.rng.random_range(0.0..1.0);
+        self.stack.push(n);
+    }
+
+    pub fn rngi(&mut self) {
+        if self.stack.len() >= 2 {
+            let first_value = self.stack.pop().unwrap();
+            let second_value = self.stack.pop().unwrap();
+
+            // The bounds may come in either order, and both are included.
+            let low = first_value.min(second_value).ceil();
+            let high = first_value.max(second_value).floor();
+            let n = if low.is_finite() && high.is_finite() && low <= high {
+                low + self.rng.random_range(0..=(high - low) as u64) as f64
+            } else {
+                f64::NAN
+            };
+            self.stack.push(n);
+        }
+    }
+
+    pub fn rngn(&mut self) {
+        // Box-Muller, with 1 - u keeping the logarithm away from zero.
+        let u1 = 1.0 - self.rng.random_range(0.0..1.0);
+        let u2 = self.rng.random_range(0.0..1.0);
+        let n = (-2.0 * f64::ln(u1)).sqrt() * (std::f64::consts::TAU * u2).cos();
         self.stack.push(n);
     }
 
+    pub fn rnge(&mut self) {
+        let u = self.rng.random_range(0.0..1.0);
+        self.stack.push(-f64::ln(1.0 - u));
+    }
+
+    pub fn shuffle(&mut self, n: usize) {
+        if self.stack.len() >= n {
+            let start = self.stack.len() - n;
+            self.stack[start..].shuffle(&mut self.rng);
+        }
+    }
+
+    pub fn choose(&mut self, n: usize) {
+        if n > 0 && self.stack.len() >= n {
+            let start = self.stack.len() - n;
+            let chosen = self.stack[start + self.rng.random_range(0..n)];
+            self.stack.truncate(start);
+            self.stack.push(chosen);
+        }
+    }
+
+    /// Restarts the random sequence from `seed`.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = SplitMix64::new(seed);
+    }
+
     pub fn clear(&mut self) {
         self.expr.clear();
+        self.spans.clear();
+        self.decompiled = None;
         self.stack.clear();
-        for register in &mut self.memory {
-            *register = None;
+        self.memory.fill(None);
+        if let Some(trace) = &mut self.trace {
+            trace.clear();
         }
+        self.trace_list_state.select(None);
+        self.history.clear();
+        self.rng = SplitMix64::new(self.seed);
+    }
+
+    /// Swaps in a whole new program and resets the VM, as if it had been typed in and loaded.
+    pub fn replace_program(&mut self, expr: Vec<Instruction>) {
+        self.clear();
+        self.instruction_list_state.select(None);
+        self.spans = vec![None; expr.len()];
+        self.expr = expr;
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum CalcMode {
     INFIX,
     POSTFIX, // Reverse Polish Notation
     RAW,
 }
 
+impl CalcMode {
+    /// Name used for the mode in files and on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            CalcMode::INFIX => "infix",
+            CalcMode::POSTFIX => "postfix",
+            CalcMode::RAW => "raw",
+        }
+    }
+}
+
+impl FromStr for CalcMode {
+    type Err = UnknownMode;
+
+    /// Reads a name written by [`CalcMode::name`], ignoring case.
+    fn from_str(name: &str) -> Result<CalcMode, UnknownMode> {
+        match name.to_ascii_lowercase().as_str() {
+            "infix" => Ok(CalcMode::INFIX),
+            "postfix" => Ok(CalcMode::POSTFIX),
+            "raw" => Ok(CalcMode::RAW),
+            _ => Err(UnknownMode(name.to_string())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownMode(pub String);
+
+impl fmt::Display for UnknownMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Unknown mode \"{}\", expected infix, postfix or raw",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnknownMode {}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LoadMode {
+    Replace,
+    Append,
+}
+
 #[derive(Clone, PartialOrd, PartialEq)]
 pub enum InputMode {
     Normal,
     Insert,
+    Command,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Panel {
+    Cfg,
+    Optimised,
+    Tac,
+    Bytecode,
+    Trace,
 }
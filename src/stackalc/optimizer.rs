@@ -0,0 +1,201 @@
+use crate::stackalc::ast::BinaryOp;
+use crate::stackalc::instructions::Instruction;
+use std::collections::HashSet;
+
+/// Runs the peephole passes until none of them changes the program any more.
+pub fn optimize(expr: &[Instruction]) -> Vec<Instruction> {
+    let mut program = expr.to_vec();
+
+    while peephole(&mut program) {}
+
+    program
+}
+
+/// One sweep over the program. Rewritten windows never start or continue at a branch target,
+/// so removing instructions only needs the targets to be remapped afterwards.
+fn peephole(program: &mut Vec<Instruction>) -> bool {
+    let targets: HashSet<usize> = program
+        .iter()
+        .filter_map(Instruction::branch_target)
+        .collect();
+    let is_target = |offset: usize| targets.contains(&offset);
+
+    let mut slots: Vec<Option<Instruction>> = program.iter().cloned().map(Some).collect();
+    let mut changed = false;
+    let mut offset = 0;
+
+    while offset < slots.len() {
+        let window = |len: usize| -> Option<Vec<Instruction>> {
+            if offset + len > slots.len() || (1..len).any(|i| is_target(offset + i)) {
+                return None;
+            }
+            slots[offset..offset + len].iter().cloned().collect()
+        };
+
+        if let Some([Instruction::LDC(lhs), Instruction::LDC(rhs), op]) = window(3).as_deref()
+            && let Some(op) = BinaryOp::from_instruction(op)
+        {
+            slots[offset] = Some(Instruction::LDC(op.apply(*lhs, *rhs)));
+            slots[offset + 1] = None;
+            slots[offset + 2] = None;
+            changed = true;
+            offset += 3;
+            continue;
+        }
+
+        match window(2).as_deref() {
+            Some([Instruction::LDC(n), Instruction::NEG]) => {
+                slots[offset] = Some(Instruction::LDC(-n));
+                slots[offset + 1] = None;
+                changed = true;
+                offset += 2;
+                continue;
+            }
+            Some([Instruction::LDC(0.0), Instruction::ADD])
+            | Some([Instruction::DUP, Instruction::POP])
+            | Some([Instruction::NEG, Instruction::NEG]) => {
+                slots[offset] = None;
+                slots[offset + 1] = None;
+                changed = true;
+                offset += 2;
+                continue;
+            }
+            _ => {}
+        }
+
+        match slots[offset].clone() {
+            Some(Instruction::BR(target)) if target == offset + 1 => {
+                slots[offset] = None;
+                changed = true;
+            }
+            Some(Instruction::BRTRUE(target) | Instruction::BRFALSE(target))
+                if target == offset + 1 =>
+            {
+                slots[offset] = Some(Instruction::POP);
+                changed = true;
+            }
+            Some(mut instruction) => {
+                if let Some(target) = instruction.branch_target() {
+                    let threaded = thread_jump(program, target);
+                    if threaded != target {
+                        instruction.retarget(threaded);
+                        slots[offset] = Some(instruction);
+                        changed = true;
+                    }
+                }
+            }
+            None => {}
+        }
+
+        offset += 1;
+    }
+
+    if changed {
+        *program = compact(slots);
+    }
+    changed
+}
+
+/// Follows a chain of unconditional branches, stopping if it loops back on itself.
+fn thread_jump(program: &[Instruction], target: usize) -> usize {
+    let mut current = target;
+    let mut visited = HashSet::new();
+
+    while let Some(Instruction::BR(next)) = program.get(current) {
+        if !visited.insert(current) || *next > program.len() {
+            break;
+        }
+        current = *next;
+    }
+
+    if visited.contains(&current) {
+        target
+    } else {
+        current
+    }
+}
+
+/// Drops removed slots and points every branch at the first surviving instruction at or after
+/// its old target.
+fn compact(slots: Vec<Option<Instruction>>) -> Vec<Instruction> {
+    let mut remap = vec![0; slots.len() + 1];
+    let mut kept = 0;
+    for (offset, slot) in slots.iter().enumerate() {
+        remap[offset] = kept;
+        if slot.is_some() {
+            kept += 1;
+        }
+    }
+    remap[slots.len()] = kept;
+
+    slots
+        .into_iter()
+        .flatten()
+        .map(|mut instruction| {
+            if let Some(target) = instruction.branch_target()
+                && let Some(&new_target) = remap.get(target)
+            {
+                instruction.retarget(new_target);
+            }
+            instruction
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stackalc::{CalcMode, Stackalc};
+
+    fn parse(source: &str) -> Vec<Instruction> {
+        let mut stackalc = Stackalc::default();
+        stackalc.parse(CalcMode::RAW, source).unwrap();
+        stackalc.expr
+    }
+
+    fn run(expr: &[Instruction]) -> Vec<f64> {
+        let mut stackalc = Stackalc::default();
+        stackalc.replace_program(expr.to_vec());
+        stackalc.run_to_completion(1000).unwrap();
+        stackalc.stack
+    }
+
+    #[test]
+    fn constant_folding_keeps_the_result() {
+        let expr = parse("ldc:10 ldc:4 sub ldc:2 div ldc:3 neg mul ldc:0 add ldc:7 ldc:7 cgt");
+        let optimized = optimize(&expr);
+
+        assert_eq!(optimized, [Instruction::LDC(-9.0), Instruction::LDC(1.0)]);
+        assert_eq!(run(&optimized), run(&expr));
+    }
+
+    #[test]
+    fn folding_agrees_with_the_vm_on_operand_order() {
+        for operator in ["add", "sub", "mul", "div", "cgt", "clt"] {
+            for (left, right) in [(10, 4), (4, 10), (5, 5)] {
+                let expr = parse(&format!("ldc:{} ldc:{} {}", left, right, operator));
+                let optimized = optimize(&expr);
+
+                assert_eq!(optimized.len(), 1, "{} was not folded", operator);
+                assert_eq!(
+                    run(&optimized),
+                    run(&expr),
+                    "ldc:{} ldc:{} {}",
+                    left,
+                    right,
+                    operator
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn jump_threading_keeps_the_result() {
+        let expr = parse("ldc:1 br:3 ldc:9 br:5 ldc:8 ldc:2 add dup brfalse:3");
+        let optimized = optimize(&expr);
+
+        assert_eq!(optimized[1], Instruction::BR(5));
+        assert_eq!(optimized[8], Instruction::BRFALSE(5));
+        assert_eq!(run(&optimized), run(&expr));
+    }
+}
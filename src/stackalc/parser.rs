@@ -1,114 +1,278 @@
-use crate::stackalc::Stackalc;
+use crate::stackalc::ast::BinaryOp;
+use crate::stackalc::codegen::{Spanned, eliminate_common_subexpressions, minimise_stack_depth};
 use crate::stackalc::instructions::Instruction;
+use crate::stackalc::registers::MAX_REGISTERS;
+use crate::stackalc::verifier::verify;
+use crate::stackalc::{CalcMode, Stackalc};
 use lazy_static::lazy_static;
 use regex::Regex;
 use std::collections::VecDeque;
 use std::fmt;
+use std::ops::Range;
 
 lazy_static! {
-    static ref LDC_RE: Regex = Regex::new(r"^ldc:(\d+(?:\.\d+)?)$").unwrap();
-    static ref LDV_RE: Regex = Regex::new(r"^ldv:(\d+)$").unwrap();
-    static ref STV_RE: Regex = Regex::new(r"^stv:(\d+)$").unwrap();
+    static ref LDC_RE: Regex = Regex::new(r"^ldc:(-?(?:\d+(?:\.\d+)?|inf)|NaN)$").unwrap();
+    static ref LDV_RE: Regex = Regex::new(r"^ldv:(\d+|[A-Za-z_]\w*)$").unwrap();
+    static ref STV_RE: Regex = Regex::new(r"^stv:(\d+|[A-Za-z_]\w*)$").unwrap();
     static ref BR_RE: Regex = Regex::new(r"^br:(\d+)$").unwrap();
     static ref BRTRUE_RE: Regex = Regex::new(r"^brtrue:(\d+)$").unwrap();
     static ref BRFALSE_RE: Regex = Regex::new(r"^brfalse:(\d+)$").unwrap();
+    static ref SHUFFLE_RE: Regex = Regex::new(r"^shuffle:(\d+)$").unwrap();
+    static ref CHOOSE_RE: Regex = Regex::new(r"^choose:(\d+)$").unwrap();
+    static ref REGISTERS_RE: Regex = Regex::new(r"^\.registers:(\d+)$").unwrap();
+    static ref ALIAS_RE: Regex = Regex::new(r"^\.alias:([A-Za-z_]\w*):(\d+)$").unwrap();
 }
 
 impl Stackalc {
-    pub fn parse_infix(&mut self, input: &String) {
+    /// Appends `input` to the program, or leaves the program untouched if any of it is invalid.
+    pub fn parse(&mut self, mode: CalcMode, input: &str) -> Result<(), ParseError> {
+        match mode {
+            CalcMode::INFIX => self.parse_infix(input),
+            CalcMode::POSTFIX => self.parse_postfix(input),
+            CalcMode::RAW => self.parse_raw(input),
+        }
+    }
+
+    /// Like `parse`, but postfix input continues from the values the program already leaves (or,
+    /// with no program, from the stack, as in the REPL) and may leave several values behind.
+    pub fn parse_continuation(&mut self, mode: CalcMode, input: &str) -> Result<(), ParseError> {
+        match mode {
+            CalcMode::POSTFIX => self.postfix(input, Some(self.available_depth())),
+            _ => self.parse(mode, input),
+        }
+    }
+
+    /// Values on the stack when the next appended instruction runs.
+    fn available_depth(&self) -> usize {
+        if self.expr.is_empty() {
+            self.stack.len()
+        } else {
+            verify(&self.expr).depths[self.expr.len()].unwrap_or(0)
+        }
+    }
+
+    pub fn parse_infix(&mut self, input: &str) -> Result<(), ParseError> {
+        let instructions = self.compile_infix(input, self.reorder)?;
+        self.extend_program(instructions);
+        Ok(())
+    }
+
+    /// Peak stack depth of `input` compiled in operand order and with Sethi-Ullman reordering.
+    pub fn infix_peak_depths(&self, input: &str) -> Option<(usize, usize)> {
+        let peak_depth = |reorder| {
+            self.compile_infix(input, reorder).ok().map(|instructions| {
+                let instructions: Vec<Instruction> = instructions
+                    .into_iter()
+                    .map(|(instruction, _)| instruction)
+                    .collect();
+                verify(&instructions).max_depth()
+            })
+        };
+
+        Some((peak_depth(false)?, peak_depth(true)?))
+    }
+
+    fn compile_infix(&self, input: &str, reorder: bool) -> Result<Vec<Spanned>, ParseError> {
         let mut instructions = Vec::new();
 
-        if let Ok(rpn) = shunting_yard(input.as_str()) {
-            for item in rpn {
-                match item {
-                    OpOrNum::Op('+') => instructions.push(Instruction::ADD),
-                    OpOrNum::Op('-') => instructions.push(Instruction::SUB),
-                    OpOrNum::Op('*') => instructions.push(Instruction::MUL),
-                    OpOrNum::Op('/') => instructions.push(Instruction::DIV),
-                    OpOrNum::Num(n) => instructions.push(Instruction::LDC(n)),
-                    _ => {}
-                }
+        for (item, span) in shunting_yard(input)? {
+            match item {
+                OpOrNum::Op('+') => instructions.push((Instruction::ADD, span)),
+                OpOrNum::Op('-') => instructions.push((Instruction::SUB, span)),
+                OpOrNum::Op('*') => instructions.push((Instruction::MUL, span)),
+                OpOrNum::Op('/') => instructions.push((Instruction::DIV, span)),
+                OpOrNum::Num(n) => instructions.push((Instruction::LDC(n), span)),
+                _ => {}
             }
+        }
 
-            self.expr.extend(instructions);
+        if reorder {
+            instructions = minimise_stack_depth(instructions);
+        }
+        if self.cse {
+            let free_registers = self.free_registers();
+            instructions = eliminate_common_subexpressions(instructions, &free_registers);
         }
+
+        Ok(instructions)
     }
 
-    pub fn parse_postfix(&mut self, input: &String) {
+    /// Registers that hold no value and that the program never touches, highest first.
+    fn free_registers(&self) -> Vec<usize> {
+        (0..self.memory.len())
+            .rev()
+            .filter(|&register| self.memory[register].is_none())
+            .filter(|&register| {
+                !self.expr.iter().any(|instruction| {
+                    matches!(instruction, Instruction::LDV(n) | Instruction::STV(n) if *n == register)
+                })
+            })
+            .collect()
+    }
+
+    pub fn parse_postfix(&mut self, input: &str) -> Result<(), ParseError> {
+        self.postfix(input, None)
+    }
+
+    /// With `available` set, the input may use that many values it did not push itself.
+    fn postfix(&mut self, input: &str, available: Option<usize>) -> Result<(), ParseError> {
         let mut instructions = Vec::new();
 
-        for token in input.split_whitespace() {
+        for (span, token) in tokens(input) {
             match token {
-                "+" => instructions.push(Instruction::ADD),
-                "-" => instructions.push(Instruction::SUB),
-                "*" => instructions.push(Instruction::MUL),
-                "/" => instructions.push(Instruction::DIV),
-                _ => {
-                    if let Ok(n) = token.parse::<f64>() {
-                        instructions.push(Instruction::LDC(n));
-                    }
-                }
+                "+" => instructions.push((Instruction::ADD, span)),
+                "-" => instructions.push((Instruction::SUB, span)),
+                "*" => instructions.push((Instruction::MUL, span)),
+                "/" => instructions.push((Instruction::DIV, span)),
+                _ => match token.parse::<f64>() {
+                    Ok(n) => instructions.push((Instruction::LDC(n), span)),
+                    Err(_) => return Err(ParseError::UnknownToken(token.to_string())),
+                },
             }
         }
+        check_arity(
+            instructions.iter().map(|(instruction, _)| {
+                BinaryOp::from_instruction(instruction).map(|op| op.symbol().to_string())
+            }),
+            available,
+        )?;
 
-        self.expr.extend(instructions);
-    }
-
-    pub fn parse_raw(&mut self, input: &str) {
-        let instructions: Vec<Instruction> = input
-            .split_whitespace()
-            .filter_map(|token| {
-                if let Some(captures) = LDC_RE.captures(token) {
-                    captures[1].parse::<f64>().ok().map(Instruction::LDC)
-                } else if let Some(captures) = LDV_RE.captures(token) {
-                    captures[1].parse::<usize>().ok().map(Instruction::LDV)
-                } else if let Some(captures) = STV_RE.captures(token) {
-                    captures[1].parse::<usize>().ok().map(Instruction::STV)
-                } else if let Some(captures) = BR_RE.captures(token) {
-                    captures[1].parse::<usize>().ok().map(Instruction::BR)
-                } else if let Some(captures) = BRTRUE_RE.captures(token) {
-                    captures[1].parse::<usize>().ok().map(Instruction::BRTRUE)
-                } else if let Some(captures) = BRFALSE_RE.captures(token) {
-                    captures[1].parse::<usize>().ok().map(Instruction::BRFALSE)
-                } else {
-                    match token {
-                        "add" => Some(Instruction::ADD),
-                        "sub" => Some(Instruction::SUB),
-                        "mul" => Some(Instruction::MUL),
-                        "div" => Some(Instruction::DIV),
-                        "neg" => Some(Instruction::NEG),
-                        "ceq" => Some(Instruction::CEQ),
-                        "cgt" => Some(Instruction::CGT),
-                        "clt" => Some(Instruction::CLT),
-                        "dup" => Some(Instruction::DUP),
-                        "pop" => Some(Instruction::POP),
-                        "nop" => Some(Instruction::NOP),
-                        "rng" => Some(Instruction::RNG),
-                        _ => None,
-                    }
+        self.extend_program(instructions);
+        Ok(())
+    }
+
+    /// Besides instructions, RAW input may contain `.registers:<count>` to resize the register
+    /// file and `.alias:<name>:<index>` to name a register, after which `ldv:<name>` and
+    /// `stv:<name>` refer to it. Directives take effect in order, and only if the whole input
+    /// parses.
+    pub fn parse_raw(&mut self, input: &str) -> Result<(), ParseError> {
+        let mut registers = self.memory.len();
+        let mut aliases = self.aliases.clone();
+        let mut instructions = Vec::new();
+
+        for (span, token) in tokens(input) {
+            if let Some(captures) = REGISTERS_RE.captures(token) {
+                registers = captures[1]
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|&count| count <= MAX_REGISTERS)
+                    .ok_or_else(|| ParseError::InvalidRegisterCount(captures[1].to_string()))?;
+                aliases.retain(|_, register| *register < registers);
+                continue;
+            }
+            if let Some(captures) = ALIAS_RE.captures(token) {
+                let register = captures[2]
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|&register| register < registers)
+                    .ok_or_else(|| ParseError::InvalidRegister(captures[2].to_string()))?;
+                aliases.insert(captures[1].to_string(), register);
+                continue;
+            }
+
+            let register = |operand: &str| {
+                operand
+                    .parse::<usize>()
+                    .ok()
+                    .or_else(|| aliases.get(operand).copied())
+                    .ok_or_else(|| ParseError::UnknownRegister(operand.to_string()))
+            };
+            let instruction = if let Some(captures) = LDC_RE.captures(token) {
+                captures[1].parse::<f64>().ok().map(Instruction::LDC)
+            } else if let Some(captures) = LDV_RE.captures(token) {
+                Some(Instruction::LDV(register(&captures[1])?))
+            } else if let Some(captures) = STV_RE.captures(token) {
+                Some(Instruction::STV(register(&captures[1])?))
+            } else if let Some(captures) = BR_RE.captures(token) {
+                captures[1].parse::<usize>().ok().map(Instruction::BR)
+            } else if let Some(captures) = BRTRUE_RE.captures(token) {
+                captures[1].parse::<usize>().ok().map(Instruction::BRTRUE)
+            } else if let Some(captures) = BRFALSE_RE.captures(token) {
+                captures[1].parse::<usize>().ok().map(Instruction::BRFALSE)
+            } else if let Some(captures) = SHUFFLE_RE.captures(token) {
+                captures[1].parse::<usize>().ok().map(Instruction::SHUFFLE)
+            } else if let Some(captures) = CHOOSE_RE.captures(token) {
+                captures[1].parse::<usize>().ok().map(Instruction::CHOOSE)
+            } else {
+                match token {
+                    "add" => Some(Instruction::ADD),
+                    "sub" => Some(Instruction::SUB),
+                    "mul" => Some(Instruction::MUL),
+                    "div" => Some(Instruction::DIV),
+                    "neg" => Some(Instruction::NEG),
+                    "ceq" => Some(Instruction::CEQ),
+                    "cgt" => Some(Instruction::CGT),
+                    "clt" => Some(Instruction::CLT),
+                    "dup" => Some(Instruction::DUP),
+                    "pop" => Some(Instruction::POP),
+                    "nop" => Some(Instruction::NOP),
+                    "rng" => Some(Instruction::RNG),
+                    "rngi" => Some(Instruction::RNGI),
+                    "rngn" => Some(Instruction::RNGN),
+                    "rnge" => Some(Instruction::RNGE),
+                    _ => None,
                 }
+            };
+            let instruction =
+                instruction.ok_or_else(|| ParseError::UnknownInstruction(token.to_string()))?;
+            instructions.push((instruction, span));
+        }
+
+        // Checked once the input is read, since a later `.registers` may shrink the file, and
+        // against the program being appended to as well, which has to fit the new size too.
+        if let Some(register) = self
+            .expr
+            .iter()
+            .chain(instructions.iter().map(|(instruction, _)| instruction))
+            .find_map(|instruction| match instruction {
+                Instruction::LDV(register) | Instruction::STV(register)
+                    if *register >= registers =>
+                {
+                    Some(*register)
+                }
+                _ => None,
             })
-            .collect();
+        {
+            return Err(ParseError::InvalidRegister(register.to_string()));
+        }
 
-        self.expr.extend(instructions);
+        self.set_register_count(registers);
+        self.aliases = aliases;
+        self.extend_program(instructions);
+        Ok(())
     }
+
+    fn extend_program(&mut self, instructions: Vec<Spanned>) {
+        for (instruction, span) in instructions {
+            self.expr.push(instruction);
+            self.spans.push(Some(span));
+        }
+    }
+}
+
+/// Splits `input` on whitespace, keeping the byte range of every token.
+fn tokens(input: &str) -> impl Iterator<Item = (Range<usize>, &str)> {
+    input.split_whitespace().map(move |token| {
+        let start = token.as_ptr() as usize - input.as_ptr() as usize;
+        (start..start + token.len(), token)
+    })
 }
 
-fn shunting_yard(expression: &str) -> Result<Vec<OpOrNum>, ParseError> {
+fn shunting_yard(expression: &str) -> Result<Vec<(OpOrNum, Range<usize>)>, ParseError> {
     if expression.trim().is_empty() {
         return Err(ParseError::EmptyExpression);
     }
 
     let mut output = Vec::new();
     let mut operators = VecDeque::new();
-    let mut token_iter = expression.chars().peekable();
+    let mut token_iter = expression.char_indices().peekable();
 
-    while let Some(&c) = token_iter.peek() {
+    while let Some(&(start, c)) = token_iter.peek() {
         if c.is_whitespace() {
             token_iter.next();
         } else if c.is_numeric() || c == '.' {
             let mut num_str = String::new();
-            while let Some(&ch) = token_iter.peek() {
+            while let Some(&(_, ch)) = token_iter.peek() {
                 if ch.is_numeric() || ch == '.' {
                     num_str.push(ch);
                     token_iter.next();
@@ -118,20 +282,21 @@ fn shunting_yard(expression: &str) -> Result<Vec<OpOrNum>, ParseError> {
             }
             let num = num_str
                 .parse::<f64>()
-                .map_err(|_| ParseError::InvalidNumber(num_str))?;
-            output.push(OpOrNum::Num(num));
+                .map_err(|_| ParseError::InvalidNumber(num_str.clone()))?;
+            output.push((OpOrNum::Num(num), start..start + num_str.len()));
         } else if is_operator(c) || c == '(' || c == ')' {
             token_iter.next();
+            let span = start..start + c.len_utf8();
             match c {
-                '(' => operators.push_back(c),
+                '(' => operators.push_back((c, span)),
                 ')' => {
                     let mut found_paren = false;
-                    while let Some(op) = operators.pop_back() {
+                    while let Some((op, op_span)) = operators.pop_back() {
                         if op == '(' {
                             found_paren = true;
                             break;
                         } else {
-                            output.push(OpOrNum::Op(op));
+                            output.push((OpOrNum::Op(op), op_span));
                         }
                     }
                     if !found_paren {
@@ -139,14 +304,15 @@ fn shunting_yard(expression: &str) -> Result<Vec<OpOrNum>, ParseError> {
                     }
                 }
                 _ => {
-                    while let Some(&top) = operators.back() {
+                    while let Some(&(top, _)) = operators.back() {
                         if top != '(' && precedence(top) >= precedence(c) {
-                            output.push(OpOrNum::Op(operators.pop_back().unwrap()));
+                            let (op, op_span) = operators.pop_back().unwrap();
+                            output.push((OpOrNum::Op(op), op_span));
                         } else {
                             break;
                         }
                     }
-                    operators.push_back(c);
+                    operators.push_back((c, span));
                 }
             }
         } else {
@@ -154,16 +320,49 @@ fn shunting_yard(expression: &str) -> Result<Vec<OpOrNum>, ParseError> {
         }
     }
 
-    while let Some(op) = operators.pop_back() {
+    while let Some((op, span)) = operators.pop_back() {
         if op == '(' {
             return Err(ParseError::MismatchedParentheses);
         }
-        output.push(OpOrNum::Op(op));
+        output.push((OpOrNum::Op(op), span));
     }
 
+    check_arity(
+        output.iter().map(|(item, _)| match item {
+            OpOrNum::Num(_) => None,
+            OpOrNum::Op(op) => Some(op.to_string()),
+        }),
+        None,
+    )?;
     Ok(output)
 }
 
+/// Checks that every operator in a postfix sequence has its two operands and that the whole
+/// sequence leaves exactly one value. Operands are `None`, operators their name. A sequence that
+/// continues from `available` values on the stack may use them and leave any number behind.
+fn check_arity(
+    items: impl Iterator<Item = Option<String>>,
+    available: Option<usize>,
+) -> Result<(), ParseError> {
+    let mut depth = available.unwrap_or(0);
+    let mut empty = true;
+    for item in items {
+        empty = false;
+        match item {
+            None => depth += 1,
+            Some(op) if depth < 2 => return Err(ParseError::MissingOperand(op)),
+            Some(_) => depth -= 1,
+        }
+    }
+
+    match (available, depth) {
+        _ if empty => Err(ParseError::EmptyExpression),
+        (Some(_), _) | (None, 1) => Ok(()),
+        (None, 0) => Err(ParseError::EmptyExpression),
+        (None, _) => Err(ParseError::MissingOperator),
+    }
+}
+
 #[derive(Debug)]
 pub enum OpOrNum {
     Op(char),
@@ -188,6 +387,13 @@ pub enum ParseError {
     InvalidNumber(String),
     UnexpectedCharacter(char),
     EmptyExpression,
+    UnknownToken(String),
+    MissingOperand(String),
+    MissingOperator,
+    UnknownInstruction(String),
+    UnknownRegister(String),
+    InvalidRegister(String),
+    InvalidRegisterCount(String),
 }
 
 impl fmt::Display for ParseError {
@@ -197,8 +403,127 @@ impl fmt::Display for ParseError {
             ParseError::InvalidNumber(num_str) => write!(f, "Invalid number: '{}'", num_str),
             ParseError::UnexpectedCharacter(c) => write!(f, "Unexpected character: '{}'", c),
             ParseError::EmptyExpression => write!(f, "Empty expression"),
+            ParseError::UnknownToken(token) => write!(f, "Unknown token: '{}'", token),
+            ParseError::MissingOperand(op) => write!(f, "Missing operand for '{}'", op),
+            ParseError::MissingOperator => write!(f, "Missing operator between operands"),
+            ParseError::UnknownInstruction(token) => {
+                write!(f, "Unknown instruction: '{}'", token)
+            }
+            ParseError::UnknownRegister(name) => write!(f, "Unknown register: '{}'", name),
+            ParseError::InvalidRegister(register) => {
+                write!(f, "Register {} is outside the register file", register)
+            }
+            ParseError::InvalidRegisterCount(count) => write!(
+                f,
+                "Invalid register count {}, at most {} are supported",
+                count, MAX_REGISTERS
+            ),
         }
     }
 }
 
 impl std::error::Error for ParseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stackalc::CalcMode;
+
+    #[test]
+    fn shrinking_registers_checks_the_program_appended_to() {
+        let mut stackalc = Stackalc::default();
+        stackalc.parse(CalcMode::RAW, "ldc:1 stv:5").unwrap();
+
+        assert!(matches!(
+            stackalc.parse(CalcMode::RAW, ".registers:2 ldc:2 stv:1"),
+            Err(ParseError::InvalidRegister(register)) if register == "5"
+        ));
+        assert_eq!(stackalc.expr.len(), 2);
+        assert_eq!(stackalc.memory.len(), 32);
+    }
+
+    #[test]
+    fn names_and_size_survive_clearing() {
+        let mut stackalc = Stackalc::default();
+        stackalc
+            .parse(CalcMode::RAW, ".registers:4 .alias:total:3 ldc:5 stv:total")
+            .unwrap();
+        stackalc.clear();
+
+        stackalc.parse(CalcMode::RAW, "ldc:1 stv:total").unwrap();
+        assert_eq!(stackalc.expr[1], Instruction::STV(3));
+        assert_eq!(stackalc.memory.len(), 4);
+    }
+
+    #[test]
+    fn directives_resize_and_name_registers() {
+        let mut stackalc = Stackalc::default();
+        stackalc
+            .parse(
+                CalcMode::RAW,
+                ".registers:8 .alias:x:7 .alias:y:0 ldc:1 stv:x ldv:y",
+            )
+            .unwrap();
+
+        assert_eq!(stackalc.memory.len(), 8);
+        assert_eq!(
+            stackalc.expr[1..],
+            [Instruction::STV(7), Instruction::LDV(0)]
+        );
+        assert_eq!(stackalc.register_label(7), "r7 (x)");
+    }
+
+    #[test]
+    fn bad_directives_change_nothing() {
+        let mut stackalc = Stackalc::default();
+        let mut fails = |input: &str| stackalc.parse(CalcMode::RAW, input).unwrap_err();
+
+        assert!(matches!(
+            fails(".registers:5000"),
+            ParseError::InvalidRegisterCount(_)
+        ));
+        assert!(matches!(
+            fails(".registers:4 .alias:x:4"),
+            ParseError::InvalidRegister(_)
+        ));
+        assert!(matches!(
+            fails("ldv:x .alias:x:1"),
+            ParseError::UnknownRegister(_)
+        ));
+        assert!(matches!(
+            fails(".alias:x:31 .registers:16 ldv:x"),
+            ParseError::UnknownRegister(_)
+        ));
+        assert!(matches!(
+            fails(".alias:x:1 ldc:1 bogus"),
+            ParseError::UnknownInstruction(_)
+        ));
+
+        assert_eq!(stackalc.memory.len(), 32);
+        assert!(stackalc.aliases.is_empty());
+        assert!(stackalc.expr.is_empty());
+    }
+
+    fn span_texts(mode: CalcMode, input: &str) -> Vec<&str> {
+        let mut stackalc = Stackalc::default();
+        stackalc.parse(mode, input).unwrap();
+        stackalc
+            .spans
+            .iter()
+            .map(|span| &input[span.clone().unwrap()])
+            .collect()
+    }
+
+    #[test]
+    fn instructions_map_back_to_their_source() {
+        assert_eq!(
+            span_texts(CalcMode::INFIX, "3 + 4*2"),
+            ["3", "4", "2", "*", "+"]
+        );
+        assert_eq!(span_texts(CalcMode::POSTFIX, " 10  4 -"), ["10", "4", "-"]);
+        assert_eq!(
+            span_texts(CalcMode::RAW, "ldc:1\tdup  add"),
+            ["ldc:1", "dup", "add"]
+        );
+    }
+}
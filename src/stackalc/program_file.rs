@@ -0,0 +1,170 @@
+use crate::stackalc::instructions::Instruction;
+use crate::stackalc::registers::DEFAULT_REGISTERS;
+use crate::stackalc::{CalcMode, UnknownMode};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+const MODE_HEADER: &str = "# mode:";
+
+/// A program file with its comments stripped and its lines joined into one input line.
+pub struct ProgramText {
+    pub mode: Option<CalcMode>,
+    pub source: String,
+}
+
+/// Reads the mode from a `# mode: infix|postfix|raw` header line, falling back to the file
+/// extension (`.sk` for RAW assembly, `.infix` and `.postfix` for expressions). Lines starting
+/// with `#` are comments.
+pub fn parse(path: &Path, contents: &str) -> Result<ProgramText, ProgramFileError> {
+    let mut mode = None;
+    let mut lines = Vec::new();
+
+    for line in contents.lines().map(str::trim) {
+        if let Some(name) = line.strip_prefix(MODE_HEADER) {
+            mode = Some(name.trim().parse()?);
+        } else if !line.is_empty() && !line.starts_with('#') {
+            lines.push(line);
+        }
+    }
+
+    let mode = mode.or_else(|| {
+        let extension = path.extension()?.to_str()?;
+        match extension {
+            "sk" => Some(CalcMode::RAW),
+            _ => extension.parse().ok(),
+        }
+    });
+
+    Ok(ProgramText {
+        mode,
+        source: lines.join(" "),
+    })
+}
+
+/// Writes the program as RAW assembly, one instruction per line, behind a mode header. A register
+/// file of other than the default size and register aliases are declared up front, and registers
+/// with an alias are referred to by it.
+pub fn render(expr: &[Instruction], registers: usize, aliases: &BTreeMap<String, usize>) -> String {
+    let mut contents = format!("{} raw\n", MODE_HEADER);
+    if registers != DEFAULT_REGISTERS {
+        contents.push_str(&format!(".registers:{}\n", registers));
+    }
+    for (name, register) in aliases {
+        contents.push_str(&format!(".alias:{}:{}\n", name, register));
+    }
+
+    let alias = |register: usize| {
+        aliases
+            .iter()
+            .find(|(_, aliased)| **aliased == register)
+            .map(|(name, _)| name)
+    };
+    for instruction in expr {
+        let line = match instruction {
+            Instruction::LDV(register) if let Some(name) = alias(*register) => {
+                format!("ldv:{}", name)
+            }
+            Instruction::STV(register) if let Some(name) = alias(*register) => {
+                format!("stv:{}", name)
+            }
+            instruction => instruction.to_string(),
+        };
+        contents.push_str(&line);
+        contents.push('\n');
+    }
+    contents
+}
+
+#[derive(Debug)]
+pub enum ProgramFileError {
+    UnknownMode(UnknownMode),
+}
+
+impl From<UnknownMode> for ProgramFileError {
+    fn from(err: UnknownMode) -> Self {
+        ProgramFileError::UnknownMode(err)
+    }
+}
+
+impl fmt::Display for ProgramFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProgramFileError::UnknownMode(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ProgramFileError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stackalc::Stackalc;
+
+    #[test]
+    fn non_finite_constants_survive_a_round_trip() {
+        let expr = [
+            Instruction::LDC(f64::NAN),
+            Instruction::LDC(f64::INFINITY),
+            Instruction::LDC(f64::NEG_INFINITY),
+        ];
+        let text = parse(
+            Path::new("program.sk"),
+            &render(&expr, DEFAULT_REGISTERS, &BTreeMap::new()),
+        )
+        .unwrap();
+
+        let mut stackalc = Stackalc::default();
+        stackalc.parse(text.mode.unwrap(), &text.source).unwrap();
+        assert!(matches!(stackalc.expr[0], Instruction::LDC(value) if value.is_nan()));
+        assert!(matches!(stackalc.expr[1], Instruction::LDC(f64::INFINITY)));
+        assert!(matches!(
+            stackalc.expr[2],
+            Instruction::LDC(f64::NEG_INFINITY)
+        ));
+    }
+
+    #[test]
+    fn the_header_wins_over_the_extension() {
+        let text = parse(
+            Path::new("sum.sk"),
+            "# adds two numbers\n# mode: INFIX\n1 +\n\n  2\n",
+        )
+        .unwrap();
+        assert!(matches!(text.mode, Some(CalcMode::INFIX)));
+        assert_eq!(text.source, "1 + 2");
+
+        let text = parse(Path::new("sum.postfix"), "1 2 +").unwrap();
+        assert!(matches!(text.mode, Some(CalcMode::POSTFIX)));
+        assert!(parse(Path::new("sum.txt"), "1 2 +").unwrap().mode.is_none());
+
+        assert!(matches!(
+            parse(Path::new("sum.sk"), "# mode: prefix\n+ 1 2"),
+            Err(ProgramFileError::UnknownMode(_))
+        ));
+    }
+
+    #[test]
+    fn registers_and_aliases_survive_a_round_trip() {
+        let mut original = Stackalc::default();
+        original
+            .parse(
+                CalcMode::RAW,
+                ".registers:4 .alias:total:3 ldc:2 stv:total ldv:total ldv:3 stv:0",
+            )
+            .unwrap();
+
+        let contents = render(&original.expr, original.memory.len(), &original.aliases);
+        assert!(
+            contents.starts_with("# mode: raw\n.registers:4\n.alias:total:3\nldc:2\nstv:total\n")
+        );
+
+        let text = parse(Path::new("program.sk"), &contents).unwrap();
+        let mut reopened = Stackalc::default();
+        reopened.parse(text.mode.unwrap(), &text.source).unwrap();
+        assert_eq!(reopened.expr, original.expr);
+        assert_eq!(reopened.memory.len(), 4);
+        assert_eq!(reopened.aliases, original.aliases);
+    }
+}
@@ -0,0 +1,136 @@
+use rand::RngCore;
+use rand::rand_core::impls;
+
+/// SplitMix64: small, fast and fully described by one `u64`, so a snapshot can store the exact
+/// point a random sequence has reached and runs can be reproduced from their seed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SplitMix64 {
+    pub state: u64,
+}
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> SplitMix64 {
+        SplitMix64 { state: seed }
+    }
+}
+
+impl RngCore for SplitMix64 {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn fill_bytes(&mut self, dst: &mut [u8]) {
+        impls::fill_bytes_via_next(self, dst)
+    }
+}
+
+/// A fresh seed for a VM nobody asked to seed. Kept to 32 bits so it is easy to read off the
+/// footer and type back in.
+pub fn random_seed() -> u64 {
+    rand::random::<u32>() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stackalc::runtime::RuntimeError;
+    use crate::stackalc::{CalcMode, Stackalc};
+
+    fn seeded(seed: u64) -> Stackalc {
+        let mut stackalc = Stackalc::default();
+        stackalc.parse(CalcMode::RAW, "rng rng rng").unwrap();
+        stackalc.set_seed(seed);
+        stackalc
+    }
+
+    #[test]
+    fn a_seed_gives_the_same_values_every_run() {
+        let run = |seed| {
+            let mut stackalc = seeded(seed);
+            stackalc.run_to_completion(10).unwrap();
+            stackalc.stack
+        };
+
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+        assert!(run(7).iter().all(|value| (0.0..1.0).contains(value)));
+    }
+
+    #[test]
+    fn stepping_back_rewinds_the_random_sequence() {
+        let mut stackalc = seeded(7);
+        stackalc.next();
+        stackalc.next();
+        let forward = stackalc.stack.clone();
+
+        stackalc.previous();
+        assert_eq!(stackalc.stack, forward[..1]);
+        stackalc.next();
+        assert_eq!(stackalc.stack, forward);
+    }
+
+    fn run(source: &str, seed: u64) -> Result<Vec<f64>, RuntimeError> {
+        let mut stackalc = Stackalc::default();
+        stackalc.parse(CalcMode::RAW, source).unwrap();
+        stackalc.set_seed(seed);
+        stackalc.run_to_completion(100)?;
+        Ok(stackalc.stack)
+    }
+
+    #[test]
+    fn rngi_includes_both_bounds_in_either_order() {
+        let mut seen = Vec::new();
+        for seed in 0..200 {
+            let value = run("ldc:3 ldc:1 rngi", seed).unwrap()[0];
+            assert!([1.0, 2.0, 3.0].contains(&value), "{}", value);
+            seen.push(value);
+        }
+        assert!(seen.contains(&1.0) && seen.contains(&3.0));
+
+        assert_eq!(run("ldc:1.5 ldc:2.5 rngi", 0).unwrap(), [2.0]);
+        assert!(run("ldc:1.2 ldc:1.8 rngi", 0).unwrap()[0].is_nan());
+        assert!(run("ldc:0 ldc:inf rngi", 0).unwrap()[0].is_nan());
+    }
+
+    #[test]
+    fn shuffle_and_choose_only_touch_the_top_values() {
+        let mut shuffled = run("ldc:9 ldc:1 ldc:2 ldc:3 shuffle:3", 5).unwrap();
+        assert_eq!(shuffled[0], 9.0);
+        shuffled[1..].sort_by(f64::total_cmp);
+        assert_eq!(shuffled, [9.0, 1.0, 2.0, 3.0]);
+
+        let chosen = run("ldc:9 ldc:1 ldc:2 ldc:3 choose:3", 5).unwrap();
+        assert_eq!(chosen.len(), 2);
+        assert_eq!(chosen[0], 9.0);
+        assert!([1.0, 2.0, 3.0].contains(&chosen[1]));
+    }
+
+    #[test]
+    fn shuffle_and_choose_of_nothing_or_too_much() {
+        assert_eq!(run("ldc:1 shuffle:0 choose:0", 0).unwrap(), [1.0]);
+        assert_eq!(run("ldc:1 choose:1", 0).unwrap(), [1.0]);
+        assert!(matches!(
+            run("ldc:1 ldc:2 shuffle:3", 0),
+            Err(RuntimeError::StackUnderflow {
+                required: 3,
+                found: 2,
+                ..
+            })
+        ));
+        assert!(matches!(
+            run("ldc:1 choose:2", 0),
+            Err(RuntimeError::StackUnderflow {
+                required: 2,
+                found: 1,
+                ..
+            })
+        ));
+    }
+}
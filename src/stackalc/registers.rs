@@ -0,0 +1,33 @@
+use crate::stackalc::Stackalc;
+
+/// Size of the register file unless a program or `--registers` asks for another.
+pub const DEFAULT_REGISTERS: usize = 32;
+
+/// Upper bound on the register file, so a typo in a directive cannot exhaust memory.
+pub const MAX_REGISTERS: usize = 4096;
+
+impl Stackalc {
+    /// Grows or shrinks the register file. Values and aliases of registers that no longer exist
+    /// are dropped.
+    pub fn set_register_count(&mut self, count: usize) {
+        self.memory.resize(count, None);
+        self.aliases.retain(|_, register| *register < count);
+    }
+
+    /// Names given to `register` with `.alias`, in alphabetical order.
+    pub fn register_aliases(&self, register: usize) -> Vec<&str> {
+        self.aliases
+            .iter()
+            .filter(|(_, aliased)| **aliased == register)
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    /// `r3`, or `r3 (total)` if the register has been given a name.
+    pub fn register_label(&self, register: usize) -> String {
+        match self.register_aliases(register).as_slice() {
+            [] => format!("r{}", register),
+            names => format!("r{} ({})", register, names.join(", ")),
+        }
+    }
+}
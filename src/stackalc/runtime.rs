@@ -0,0 +1,230 @@
+use crate::stackalc::Stackalc;
+use crate::stackalc::instructions::Instruction;
+use std::fmt;
+
+/// Steps a headless run may take before it is assumed to loop forever.
+pub const STEP_LIMIT: usize = 1_000_000;
+
+impl Stackalc {
+    /// Runs the program from the start until it falls off the end. Unlike stepping in the TUI,
+    /// where instructions without their operands are skipped, anything the VM cannot do properly
+    /// stops the run with an error.
+    pub fn run_to_completion(&mut self, step_limit: usize) -> Result<(), RuntimeError> {
+        self.run_from(0, step_limit)
+    }
+
+    /// Like [`Stackalc::run_to_completion`], but starting at `pc`, as when resuming a snapshot.
+    pub fn run_from(&mut self, pc: usize, step_limit: usize) -> Result<(), RuntimeError> {
+        let mut offset = pc;
+        let mut steps = 0;
+
+        while offset < self.expr.len() {
+            if steps == step_limit {
+                return Err(RuntimeError::StepLimit {
+                    offset,
+                    limit: step_limit,
+                });
+            }
+            steps += 1;
+
+            let instruction = self.expr[offset].clone();
+            let (required, _) = instruction.stack_effect();
+            if self.stack.len() < required {
+                return Err(RuntimeError::StackUnderflow {
+                    offset,
+                    instruction,
+                    required,
+                    found: self.stack.len(),
+                });
+            }
+            if let Instruction::LDV(register) | Instruction::STV(register) = instruction {
+                if register >= self.memory.len() {
+                    return Err(RuntimeError::InvalidRegister { offset, register });
+                }
+                if matches!(instruction, Instruction::LDV(_)) && self.memory[register].is_none() {
+                    return Err(RuntimeError::EmptyRegister { offset, register });
+                }
+            }
+            if let Some(target) = instruction.branch_target()
+                && target > self.expr.len()
+            {
+                return Err(RuntimeError::InvalidBranch { offset, target });
+            }
+
+            self.instruction_list_state.select(Some(offset));
+            let stack = self.stack.clone();
+            let next = match instruction.clone() {
+                Instruction::BR(target) => target,
+                Instruction::BRTRUE(target) | Instruction::BRFALSE(target) => {
+                    let value = self.stack.pop().unwrap_or_default();
+                    let taken = match instruction {
+                        Instruction::BRTRUE(_) => value != 0.0,
+                        _ => value == 0.0,
+                    };
+                    if taken { target } else { offset + 1 }
+                }
+                instruction => {
+                    self.execute_instruction(instruction);
+                    offset + 1
+                }
+            };
+            self.record_step(offset, instruction, stack);
+            offset = next;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum RuntimeError {
+    StackUnderflow {
+        offset: usize,
+        instruction: Instruction,
+        required: usize,
+        found: usize,
+    },
+    InvalidRegister {
+        offset: usize,
+        register: usize,
+    },
+    EmptyRegister {
+        offset: usize,
+        register: usize,
+    },
+    InvalidBranch {
+        offset: usize,
+        target: usize,
+    },
+    StepLimit {
+        offset: usize,
+        limit: usize,
+    },
+}
+
+impl RuntimeError {
+    /// The instruction the run stopped at, which has not been executed.
+    pub fn offset(&self) -> usize {
+        match self {
+            RuntimeError::StackUnderflow { offset, .. }
+            | RuntimeError::InvalidRegister { offset, .. }
+            | RuntimeError::EmptyRegister { offset, .. }
+            | RuntimeError::InvalidBranch { offset, .. }
+            | RuntimeError::StepLimit { offset, .. } => *offset,
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuntimeError::StackUnderflow {
+                offset,
+                instruction,
+                required,
+                found,
+            } => write!(
+                f,
+                "Stack underflow at {:#06X}: {} needs {} values, found {}",
+                offset, instruction, required, found
+            ),
+            RuntimeError::InvalidRegister { offset, register } => {
+                write!(
+                    f,
+                    "Register r{} at {:#06X} does not exist",
+                    register, offset
+                )
+            }
+            RuntimeError::EmptyRegister { offset, register } => write!(
+                f,
+                "r{} is read at {:#06X} before anything was stored in it",
+                register, offset
+            ),
+            RuntimeError::InvalidBranch { offset, target } => write!(
+                f,
+                "Branch at {:#06X} jumps to {:#06X}, past the end of the program",
+                offset, target
+            ),
+            RuntimeError::StepLimit { limit, .. } => {
+                write!(
+                    f,
+                    "Stopped after {} steps, the program may not terminate",
+                    limit
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stackalc::CalcMode;
+
+    fn run(source: &str) -> Result<Vec<f64>, RuntimeError> {
+        let mut stackalc = Stackalc::default();
+        stackalc.parse(CalcMode::RAW, source).unwrap();
+        stackalc.run_to_completion(1000)?;
+        Ok(stackalc.stack)
+    }
+
+    #[test]
+    fn operators_take_the_top_of_the_stack_as_their_right_operand() {
+        assert_eq!(run("ldc:10 ldc:4 sub").unwrap(), [6.0]);
+        assert_eq!(run("ldc:8 ldc:2 div").unwrap(), [4.0]);
+        assert_eq!(run("ldc:8 ldc:2 cgt").unwrap(), [1.0]);
+        assert_eq!(run("ldc:2 ldc:8 cgt").unwrap(), [0.0]);
+        assert_eq!(run("ldc:2 ldc:8 clt").unwrap(), [1.0]);
+        assert_eq!(run("ldc:8 ldc:2 clt").unwrap(), [0.0]);
+    }
+
+    #[test]
+    fn comparisons_include_equality() {
+        assert_eq!(run("ldc:3 ldc:3 cgt ldc:3 ldc:3 clt").unwrap(), [1.0, 1.0]);
+    }
+
+    #[test]
+    fn errors_stop_at_the_offending_instruction() {
+        let err = run("ldc:1 add").unwrap_err();
+        assert!(matches!(
+            err,
+            RuntimeError::StackUnderflow {
+                offset: 1,
+                required: 2,
+                found: 1,
+                ..
+            }
+        ));
+
+        let err = run("ldv:3").unwrap_err();
+        assert!(matches!(
+            err,
+            RuntimeError::EmptyRegister {
+                offset: 0,
+                register: 3
+            }
+        ));
+
+        let err = run("ldc:1 br:9").unwrap_err();
+        assert!(matches!(
+            err,
+            RuntimeError::InvalidBranch {
+                offset: 1,
+                target: 9
+            }
+        ));
+    }
+
+    #[test]
+    fn endless_loops_hit_the_step_limit() {
+        let err = run("ldc:1 br:0").unwrap_err();
+        assert!(matches!(err, RuntimeError::StepLimit { limit: 1000, .. }));
+    }
+
+    #[test]
+    fn branches_to_the_end_finish_the_run() {
+        assert_eq!(run("ldc:0 brfalse:3 ldc:5").unwrap(), []);
+    }
+}
@@ -0,0 +1,197 @@
+use crate::stackalc::decompiler::decompile;
+use crate::stackalc::snapshot::{
+    Snapshot, SnapshotError, VERSION, invalid_value, parse_fields, require,
+};
+use crate::stackalc::{CalcMode, Stackalc};
+use std::ops::Range;
+use std::path::PathBuf;
+
+/// Everything needed to pick up where the user left off: the machine itself, plus the input it
+/// was compiled from and the compiler settings.
+#[derive(Clone)]
+pub struct Session {
+    pub snapshot: Snapshot,
+    pub calc_mode: CalcMode,
+    pub calculator_query: String,
+    pub spans: Vec<Option<Range<usize>>>,
+    pub cse: bool,
+    pub reorder: bool,
+    pub program_path: Option<PathBuf>,
+}
+
+/// `$HOME/.stackalc_session`, or nothing if there is no home directory to keep it in.
+pub fn default_path() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME")?;
+    Some(PathBuf::from(home).join(".stackalc_session"))
+}
+
+impl Session {
+    pub fn capture(stackalc: &Stackalc) -> Session {
+        Session {
+            snapshot: Snapshot::capture(stackalc, stackalc.pc()),
+            calc_mode: stackalc.calc_mode,
+            calculator_query: stackalc.calculator_query.clone(),
+            spans: stackalc.spans.clone(),
+            cse: stackalc.cse,
+            reorder: stackalc.reorder,
+            program_path: stackalc.program_path.clone(),
+        }
+    }
+
+    /// Puts the session back into `stackalc`, replacing its program and VM state. Fails without
+    /// touching anything if the machine does not fit.
+    pub fn restore(self, stackalc: &mut Stackalc) -> Result<(), SnapshotError> {
+        self.snapshot.restore(stackalc)?;
+
+        stackalc.spans = self.spans;
+        stackalc.spans.resize(stackalc.expr.len(), None);
+        stackalc.calc_mode = self.calc_mode;
+        stackalc.calculator_query_char_idx = self.calculator_query.chars().count();
+        stackalc.calculator_query = self.calculator_query;
+        stackalc.cse = self.cse;
+        stackalc.reorder = self.reorder;
+        stackalc.program_path = self.program_path;
+        stackalc.decompiled = match stackalc.calc_mode {
+            CalcMode::RAW => Some(decompile(&stackalc.expr)),
+            _ => None,
+        };
+
+        Ok(())
+    }
+
+    /// Writes the session as `key = value` lines, the same format as a snapshot with a few more
+    /// keys.
+    pub fn render(&self) -> String {
+        let mut lines = vec![
+            String::from("# stackalc session"),
+            format!("version = {}", VERSION),
+            format!("mode = {}", self.calc_mode.name()),
+            format!("query = {}", self.calculator_query),
+        ];
+        for (key, value) in self.snapshot.fields() {
+            lines.push(format!("{} = {}", key, value));
+        }
+        lines.extend([
+            format!(
+                "spans = {}",
+                self.spans
+                    .iter()
+                    .map(|span| match span {
+                        Some(span) => format!("{}..{}", span.start, span.end),
+                        None => String::from("-"),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+            format!("cse = {}", self.cse),
+            format!("reorder = {}", self.reorder),
+        ]);
+        if let Some(path) = &self.program_path {
+            lines.push(format!("path = {}", path.display()));
+        }
+
+        let mut contents = lines.join("\n");
+        contents.push('\n');
+        contents
+    }
+
+    pub fn parse(contents: &str) -> Result<Session, SnapshotError> {
+        let mut session = Session {
+            snapshot: Snapshot::default(),
+            calc_mode: CalcMode::INFIX,
+            calculator_query: String::new(),
+            spans: Vec::new(),
+            cse: false,
+            reorder: false,
+            program_path: None,
+        };
+
+        let fields = parse_fields(contents)?;
+        require(&fields, Snapshot::keys())?;
+        require(&fields, ["mode", "query", "spans", "cse", "reorder"])?;
+
+        for (key, value) in fields {
+            if session.snapshot.set(key, value)? {
+                continue;
+            }
+            let invalid = || invalid_value(key, value);
+
+            match key {
+                "mode" => session.calc_mode = value.parse().map_err(|_| invalid())?,
+                "query" => session.calculator_query = value.to_string(),
+                "spans" => {
+                    session.spans = value
+                        .split_whitespace()
+                        .map(|span| match span {
+                            "-" => Ok(None),
+                            span => {
+                                let (start, end) = span.split_once("..").ok_or_else(invalid)?;
+                                let start = start.parse().map_err(|_| invalid())?;
+                                let end = end.parse().map_err(|_| invalid())?;
+                                Ok(Some(start..end))
+                            }
+                        })
+                        .collect::<Result<_, _>>()?
+                }
+                "cse" => session.cse = value.parse().map_err(|_| invalid())?,
+                "reorder" => session.reorder = value.parse().map_err(|_| invalid())?,
+                "path" => session.program_path = Some(PathBuf::from(value)),
+                _ => {}
+            }
+        }
+
+        Ok(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stackalc::LoadMode;
+
+    #[test]
+    fn a_session_survives_a_round_trip() {
+        let mut stackalc = Stackalc {
+            calc_mode: CalcMode::POSTFIX,
+            calculator_query: String::from("1 2 + 3 *"),
+            reorder: true,
+            program_path: Some(PathBuf::from("/tmp/my programs/sum.postfix")),
+            ..Stackalc::default()
+        };
+        stackalc.load_input(LoadMode::Replace).unwrap();
+        stackalc.next();
+        stackalc.next();
+
+        let contents = Session::capture(&stackalc).render();
+        let mut restored = Stackalc::default();
+        Session::parse(&contents)
+            .unwrap()
+            .restore(&mut restored)
+            .unwrap();
+
+        assert!(matches!(restored.calc_mode, CalcMode::POSTFIX));
+        assert_eq!(restored.calculator_query, stackalc.calculator_query);
+        assert_eq!(restored.calculator_query_char_idx, 9);
+        assert_eq!(restored.expr, stackalc.expr);
+        assert_eq!(restored.spans, stackalc.spans);
+        assert_eq!(restored.stack, [1.0, 2.0]);
+        assert_eq!(restored.pc(), 2);
+        assert!(!restored.cse && restored.reorder);
+        assert_eq!(restored.program_path, stackalc.program_path);
+    }
+
+    #[test]
+    fn sessions_need_their_own_keys() {
+        let contents = Session::capture(&Stackalc::default()).render();
+        let without_mode: String = contents
+            .lines()
+            .filter(|line| !line.starts_with("mode = "))
+            .map(|line| format!("{}\n", line))
+            .collect();
+
+        assert!(matches!(
+            Session::parse(&without_mode),
+            Err(SnapshotError::MissingKey(key)) if key == "mode"
+        ));
+    }
+}
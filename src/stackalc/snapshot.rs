@@ -0,0 +1,384 @@
+use crate::stackalc::instructions::Instruction;
+use crate::stackalc::parser::ParseError;
+use crate::stackalc::random::SplitMix64;
+use crate::stackalc::registers::{DEFAULT_REGISTERS, MAX_REGISTERS};
+use crate::stackalc::{CalcMode, Stackalc};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Format version of snapshot and session files, to be bumped whenever the set of keys changes.
+pub const VERSION: &str = "1";
+
+/// A paused machine: the program, the offset of the next instruction to execute, the contents
+/// of the stack and registers and how far the random sequence has got. The VM has no calls, so
+/// there is no call stack to keep.
+#[derive(Clone)]
+pub struct Snapshot {
+    pub expr: Vec<Instruction>,
+    pub pc: usize,
+    pub stack: Vec<f64>,
+    pub registers: Vec<(usize, f64)>,
+    pub register_count: usize,
+    pub aliases: BTreeMap<String, usize>,
+    pub seed: u64,
+    pub rng: u64,
+}
+
+impl Default for Snapshot {
+    fn default() -> Self {
+        Self {
+            expr: Vec::new(),
+            pc: 0,
+            stack: Vec::new(),
+            registers: Vec::new(),
+            register_count: DEFAULT_REGISTERS,
+            aliases: BTreeMap::new(),
+            seed: 0,
+            rng: 0,
+        }
+    }
+}
+
+impl Stackalc {
+    /// Offset of the instruction the next step executes: the one after the selected instruction,
+    /// which is always the last one executed.
+    pub fn pc(&self) -> usize {
+        self.instruction_list_state
+            .selected()
+            .map_or(0, |selected| selected + 1)
+    }
+}
+
+impl Snapshot {
+    pub fn capture(stackalc: &Stackalc, pc: usize) -> Snapshot {
+        Snapshot {
+            expr: stackalc.expr.clone(),
+            pc,
+            stack: stackalc.stack.clone(),
+            registers: stackalc
+                .memory
+                .iter()
+                .enumerate()
+                .filter_map(|(register, value)| Some((register, (*value)?)))
+                .collect(),
+            register_count: stackalc.memory.len(),
+            aliases: stackalc.aliases.clone(),
+            seed: stackalc.seed,
+            rng: stackalc.rng.state,
+        }
+    }
+
+    /// Replaces the program and VM state of `stackalc`, so that stepping carries on from `pc`.
+    /// Fails without touching anything if the snapshot does not fit.
+    pub fn restore(self, stackalc: &mut Stackalc) -> Result<(), SnapshotError> {
+        if let Some(register) = self
+            .registers
+            .iter()
+            .map(|(register, _)| register)
+            .chain(self.aliases.values())
+            .chain(
+                self.expr
+                    .iter()
+                    .filter_map(|instruction| match instruction {
+                        Instruction::LDV(register) | Instruction::STV(register) => Some(register),
+                        _ => None,
+                    }),
+            )
+            .find(|register| **register >= self.register_count)
+        {
+            return Err(SnapshotError::RegisterOutOfRange(*register));
+        }
+        if self.pc > self.expr.len() {
+            return Err(SnapshotError::PcOutOfRange(self.pc));
+        }
+
+        stackalc.replace_program(self.expr);
+        stackalc
+            .instruction_list_state
+            .select(self.pc.checked_sub(1));
+        stackalc.stack = self.stack;
+        stackalc.set_register_count(self.register_count);
+        stackalc.aliases = self.aliases;
+        for (register, value) in self.registers {
+            stackalc.memory[register] = Some(value);
+        }
+        stackalc.seed = self.seed;
+        stackalc.rng = SplitMix64 { state: self.rng };
+
+        Ok(())
+    }
+
+    pub fn render(&self) -> String {
+        let mut contents = format!("# stackalc snapshot\nversion = {}\n", VERSION);
+        for (key, value) in self.fields() {
+            contents.push_str(&format!("{} = {}\n", key, value));
+        }
+        contents
+    }
+
+    pub fn parse(contents: &str) -> Result<Snapshot, SnapshotError> {
+        let fields = parse_fields(contents)?;
+        require(&fields, Snapshot::keys())?;
+
+        let mut snapshot = Snapshot::default();
+        for (key, value) in fields {
+            snapshot.set(key, value)?;
+        }
+        Ok(snapshot)
+    }
+
+    /// The machine state as `key = value` pairs, for files that embed a snapshot.
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("program", join(&self.expr)),
+            ("pc", self.pc.to_string()),
+            ("stack", join(&self.stack)),
+            (
+                "registers",
+                self.registers
+                    .iter()
+                    .map(|(register, value)| format!("r{}={}", register, value))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ),
+            ("register_count", self.register_count.to_string()),
+            (
+                "aliases",
+                self.aliases
+                    .iter()
+                    .map(|(name, register)| format!("{}=r{}", name, register))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ),
+            ("seed", self.seed.to_string()),
+            ("rng", self.rng.to_string()),
+        ]
+    }
+
+    /// Keys written by [`Snapshot::fields`], all of which a file has to have.
+    pub fn keys() -> Vec<&'static str> {
+        Snapshot::default()
+            .fields()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    /// Reads one of the fields written by [`Snapshot::fields`], returning whether `key` was one.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<bool, SnapshotError> {
+        let invalid = || invalid_value(key, value);
+
+        match key {
+            // `register_count` may come later, so registers are checked against it on restore.
+            "program" => {
+                let mut program = Stackalc::default();
+                program.set_register_count(MAX_REGISTERS);
+                program
+                    .parse(CalcMode::RAW, value)
+                    .map_err(SnapshotError::Program)?;
+                self.expr = program.expr;
+            }
+            "pc" => self.pc = value.parse().map_err(|_| invalid())?,
+            "stack" => {
+                self.stack = value
+                    .split_whitespace()
+                    .map(|value| value.parse().map_err(|_| invalid()))
+                    .collect::<Result<_, _>>()?
+            }
+            "registers" => {
+                self.registers = value
+                    .split_whitespace()
+                    .map(|register| {
+                        let (register, value) = register
+                            .strip_prefix('r')
+                            .and_then(|register| register.split_once('='))
+                            .ok_or_else(invalid)?;
+                        Ok((
+                            register.parse().map_err(|_| invalid())?,
+                            value.parse().map_err(|_| invalid())?,
+                        ))
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            "register_count" => {
+                self.register_count = value
+                    .parse()
+                    .ok()
+                    .filter(|&count| count <= MAX_REGISTERS)
+                    .ok_or_else(invalid)?
+            }
+            "aliases" => {
+                self.aliases = value
+                    .split_whitespace()
+                    .map(|alias| {
+                        let (name, register) = alias
+                            .split_once("=r")
+                            .filter(|(name, _)| !name.is_empty())
+                            .ok_or_else(invalid)?;
+                        Ok((name.to_string(), register.parse().map_err(|_| invalid())?))
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            "seed" => self.seed = value.parse().map_err(|_| invalid())?,
+            "rng" => self.rng = value.parse().map_err(|_| invalid())?,
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+}
+
+/// Splits a snapshot or session file into its `key = value` lines, skipping blank lines and `#`
+/// comments, and checks that it was written in a version this build reads.
+pub fn parse_fields(contents: &str) -> Result<Vec<(&str, &str)>, SnapshotError> {
+    let mut fields = Vec::new();
+    let mut version = None;
+
+    for (number, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // An empty value may have lost the space after `=` to an editor trimming lines.
+        let Some((key, value)) = line.split_once(" = ").or_else(|| line.split_once(" =")) else {
+            return Err(SnapshotError::MalformedLine(number + 1));
+        };
+
+        if key == "version" {
+            version = Some(value);
+        } else {
+            fields.push((key, value));
+        }
+    }
+
+    match version {
+        Some(VERSION) => Ok(fields),
+        version => Err(SnapshotError::UnsupportedVersion(
+            version.unwrap_or("none").to_string(),
+        )),
+    }
+}
+
+/// Fails on the first of `keys` that `fields` lacks, rather than filling in a default for it.
+pub fn require<'a>(
+    fields: &[(&str, &str)],
+    keys: impl IntoIterator<Item = &'a str>,
+) -> Result<(), SnapshotError> {
+    match keys
+        .into_iter()
+        .find(|key| fields.iter().all(|(field, _)| field != key))
+    {
+        Some(key) => Err(SnapshotError::MissingKey(key.to_string())),
+        None => Ok(()),
+    }
+}
+
+pub fn invalid_value(key: &str, value: &str) -> SnapshotError {
+    SnapshotError::InvalidValue(key.to_string(), value.to_string())
+}
+
+fn join<T: fmt::Display>(values: &[T]) -> String {
+    values
+        .iter()
+        .map(|value| value.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    UnsupportedVersion(String),
+    MalformedLine(usize),
+    MissingKey(String),
+    InvalidValue(String, String),
+    Program(ParseError),
+    RegisterOutOfRange(usize),
+    PcOutOfRange(usize),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported file version {}", version)
+            }
+            SnapshotError::MalformedLine(line) => {
+                write!(f, "Line {} is not of the form key = value", line)
+            }
+            SnapshotError::MissingKey(key) => write!(f, "Missing {}", key),
+            SnapshotError::InvalidValue(key, value) => {
+                write!(f, "Invalid {} \"{}\"", key, value)
+            }
+            SnapshotError::Program(err) => write!(f, "Invalid program: {}", err),
+            SnapshotError::RegisterOutOfRange(register) => {
+                write!(f, "Register r{} does not exist", register)
+            }
+            SnapshotError::PcOutOfRange(pc) => {
+                write!(f, "pc {:#06X} is past the end of the program", pc)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: &str =
+        ".registers:4 .alias:n:3 ldc:3 stv:n ldv:n ldc:1 sub dup stv:n brtrue:2 rng";
+
+    #[test]
+    fn resuming_a_snapshot_finishes_like_an_uninterrupted_run() {
+        let mut uninterrupted = Stackalc::default();
+        uninterrupted.parse(CalcMode::RAW, PROGRAM).unwrap();
+        uninterrupted.set_seed(42);
+        uninterrupted.run_to_completion(100).unwrap();
+
+        let mut paused = Stackalc::default();
+        paused.parse(CalcMode::RAW, PROGRAM).unwrap();
+        paused.set_seed(42);
+        let Err(err) = paused.run_to_completion(9) else {
+            panic!("the program should not finish in 9 steps");
+        };
+        let contents = Snapshot::capture(&paused, err.offset()).render();
+
+        let snapshot = Snapshot::parse(&contents).unwrap();
+        let pc = snapshot.pc;
+        let mut resumed = Stackalc::default();
+        snapshot.restore(&mut resumed).unwrap();
+        assert_eq!(resumed.memory.len(), 4);
+        assert_eq!(resumed.aliases.get("n"), Some(&3));
+
+        resumed.run_from(pc, 100).unwrap();
+        assert_eq!(resumed.stack, uninterrupted.stack);
+        assert_eq!(resumed.memory, uninterrupted.memory);
+    }
+
+    #[test]
+    fn snapshots_that_do_not_fit_are_rejected() {
+        let contents = Snapshot::default().render();
+        let restore = |contents: String| {
+            Snapshot::parse(&contents)
+                .unwrap()
+                .restore(&mut Stackalc::default())
+        };
+
+        assert!(matches!(
+            Snapshot::parse(&contents.replace("version = 1", "version = 2")),
+            Err(SnapshotError::UnsupportedVersion(version)) if version == "2"
+        ));
+        assert!(matches!(
+            Snapshot::parse(&contents.replace("rng = 0\n", "")),
+            Err(SnapshotError::MissingKey(key)) if key == "rng"
+        ));
+        assert!(matches!(
+            restore(contents.replace("pc = 0\n", "pc = 3\n")),
+            Err(SnapshotError::PcOutOfRange(3))
+        ));
+        assert!(matches!(
+            restore(contents.replace("program = \n", "program = ldv:40\n")),
+            Err(SnapshotError::RegisterOutOfRange(40))
+        ));
+    }
+}
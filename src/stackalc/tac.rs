@@ -0,0 +1,220 @@
+use crate::stackalc::ast::BinaryOp;
+use crate::stackalc::instructions::Instruction;
+use crate::stackalc::verifier::verify;
+use std::collections::BTreeSet;
+
+/// Lowers stack code to three-address code, one group of statements per instruction so the two
+/// listings line up. Every computed value gets a fresh temporary `t1`, `t2`, ...; constants and
+/// registers are used directly as operands until a store would change what they refer to.
+///
+/// Stack values that live across a branch are copied into slot variables `s0`, `s1`, ... before
+/// control leaves a block, and blocks that are jumped to read them back from there.
+pub fn lower(expr: &[Instruction]) -> Vec<Vec<String>> {
+    let depths = verify(expr).depths;
+    let targets: BTreeSet<usize> = expr.iter().filter_map(Instruction::branch_target).collect();
+
+    let mut lowering = Lowering {
+        stack: Vec::new(),
+        temps: 0,
+    };
+    let mut lines = Vec::with_capacity(expr.len());
+    let mut continues = false;
+
+    for (offset, instruction) in expr.iter().enumerate() {
+        let Some(depth) = depths[offset] else {
+            lines.push(Vec::new());
+            continues = false;
+            continue;
+        };
+        if !continues || targets.contains(&offset) {
+            lowering.stack = (0..depth).map(|slot| format!("s{}", slot)).collect();
+        }
+
+        let mut statements = lowering.lower(instruction);
+        continues = !matches!(instruction, Instruction::BR(_));
+        if continues && targets.contains(&(offset + 1)) {
+            statements.extend(lowering.spill());
+        }
+        lines.push(statements);
+    }
+
+    lines
+}
+
+struct Lowering {
+    stack: Vec<String>,
+    temps: usize,
+}
+
+impl Lowering {
+    fn temp(&mut self) -> String {
+        self.temps += 1;
+        format!("t{}", self.temps)
+    }
+
+    fn lower(&mut self, instruction: &Instruction) -> Vec<String> {
+        let mut statements = Vec::new();
+
+        match instruction {
+            Instruction::LDC(value) => self.stack.push(literal(*value)),
+            Instruction::LDV(register) => self.stack.push(format!("r{}", register)),
+            Instruction::STV(register) => {
+                let Some(value) = self.stack.pop() else {
+                    return statements;
+                };
+                // Older reads of the register still expect the value it held before this store.
+                let name = format!("r{}", register);
+                if self.stack.contains(&name) {
+                    let temp = self.temp();
+                    statements.push(format!("{} = {}", temp, name));
+                    for entry in self.stack.iter_mut().filter(|entry| **entry == name) {
+                        *entry = temp.clone();
+                    }
+                }
+                statements.push(format!("{} = {}", name, value));
+            }
+            Instruction::NEG => {
+                if let Some(value) = self.stack.pop() {
+                    let temp = self.temp();
+                    statements.push(format!("{} = -{}", temp, value));
+                    self.stack.push(temp);
+                }
+            }
+            Instruction::RNG | Instruction::RNGN | Instruction::RNGE => {
+                let temp = self.temp();
+                statements.push(format!("{} = {}()", temp, instruction));
+                self.stack.push(temp);
+            }
+            Instruction::RNGI => {
+                if self.stack.len() >= 2 {
+                    let top = self.stack.pop().unwrap();
+                    let second = self.stack.pop().unwrap();
+                    let temp = self.temp();
+                    statements.push(format!("{} = rngi({}, {})", temp, second, top));
+                    self.stack.push(temp);
+                }
+            }
+            Instruction::SHUFFLE(n) | Instruction::CHOOSE(n) => {
+                if *n == 0 || self.stack.len() < *n {
+                    return statements;
+                }
+                let operands = self.stack.split_off(self.stack.len() - n);
+                let results: Vec<String> = match instruction {
+                    Instruction::SHUFFLE(_) => (0..*n).map(|_| self.temp()).collect(),
+                    _ => vec![self.temp()],
+                };
+                let name = if matches!(instruction, Instruction::SHUFFLE(_)) {
+                    "shuffle"
+                } else {
+                    "choose"
+                };
+                statements.push(format!(
+                    "{} = {}({})",
+                    results.join(", "),
+                    name,
+                    operands.join(", ")
+                ));
+                self.stack.extend(results);
+            }
+            Instruction::DUP => {
+                if let Some(top) = self.stack.last().cloned() {
+                    self.stack.push(top);
+                }
+            }
+            Instruction::POP => {
+                self.stack.pop();
+            }
+            Instruction::NOP => {}
+            Instruction::BR(target) => {
+                statements.extend(self.spill());
+                statements.push(format!("goto {:#06X}", target));
+            }
+            Instruction::BRTRUE(target) | Instruction::BRFALSE(target) => {
+                let Some(condition) = self.stack.pop() else {
+                    return statements;
+                };
+                let comparison = match instruction {
+                    Instruction::BRTRUE(_) => "!=",
+                    _ => "==",
+                };
+                statements.extend(self.spill());
+                statements.push(format!(
+                    "if {} {} 0 goto {:#06X}",
+                    condition, comparison, target
+                ));
+            }
+            Instruction::ADD
+            | Instruction::MUL
+            | Instruction::SUB
+            | Instruction::DIV
+            | Instruction::CEQ
+            | Instruction::CGT
+            | Instruction::CLT => {
+                if self.stack.len() >= 2
+                    && let Some(op) = BinaryOp::from_instruction(instruction)
+                {
+                    let top = self.stack.pop().unwrap();
+                    let second = self.stack.pop().unwrap();
+                    let temp = self.temp();
+                    statements.push(format!("{} = {} {} {}", temp, second, op.symbol(), top));
+                    self.stack.push(temp);
+                }
+            }
+        }
+
+        statements
+    }
+
+    /// Copies the stack into the slot variables, skipping values that are already in place.
+    fn spill(&mut self) -> Vec<String> {
+        let mut statements = Vec::new();
+        for (slot, value) in self.stack.iter_mut().enumerate() {
+            let name = format!("s{}", slot);
+            if *value != name {
+                statements.push(format!("{} = {}", name, value));
+                *value = name;
+            }
+        }
+        statements
+    }
+}
+
+fn literal(value: f64) -> String {
+    if value.is_sign_negative() && value != 0.0 {
+        format!("({})", value)
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stackalc::{CalcMode, Stackalc};
+
+    fn lower_raw(source: &str) -> Vec<Vec<String>> {
+        let mut stackalc = Stackalc::default();
+        stackalc.parse(CalcMode::RAW, source).unwrap();
+        lower(&stackalc.expr)
+    }
+
+    #[test]
+    fn stores_keep_older_reads_of_the_register() {
+        let lines = lower_raw("ldc:10 ldc:-4 sub stv:0 ldv:0 ldc:1 stv:0 ldv:0 add");
+
+        assert_eq!(lines.len(), 9);
+        assert_eq!(lines[2], ["t1 = 10 - (-4)"]);
+        assert_eq!(lines[3], ["r0 = t1"]);
+        assert_eq!(lines[6], ["t2 = r0", "r0 = 1"]);
+        assert_eq!(lines[8], ["t3 = t2 + r0"]);
+    }
+
+    #[test]
+    fn values_cross_branches_in_slots() {
+        let lines = lower_raw("ldc:1 ldc:0 brfalse:5 ldc:2 add nop");
+
+        assert_eq!(lines[2], ["s0 = 1", "if 0 == 0 goto 0x0005"]);
+        assert_eq!(lines[4], ["t1 = s0 + 2", "s0 = t1"]);
+        assert!(lines[5].is_empty());
+    }
+}
@@ -0,0 +1,158 @@
+use crate::stackalc::Stackalc;
+use crate::stackalc::instructions::Instruction;
+use crate::stackalc::json::Json;
+
+/// One executed instruction with the stack around it.
+#[derive(Clone, Debug)]
+pub struct TraceEntry {
+    pub pc: usize,
+    pub instruction: Instruction,
+    pub stack_before: Vec<f64>,
+    pub stack_after: Vec<f64>,
+    /// Registers the instruction wrote, with their new values.
+    pub registers: Vec<(usize, f64)>,
+}
+
+impl TraceEntry {
+    pub fn to_json(&self, step: usize) -> Json {
+        Json::object([
+            ("step", Json::Number(step as f64)),
+            ("pc", Json::Number(self.pc as f64)),
+            ("instruction", Json::String(self.instruction.to_string())),
+            ("stack_before", Json::numbers(&self.stack_before)),
+            ("stack_after", Json::numbers(&self.stack_after)),
+            (
+                "registers",
+                Json::registers(
+                    self.registers
+                        .iter()
+                        .map(|(register, value)| (*register, value)),
+                ),
+            ),
+        ])
+    }
+
+    /// Register writes as `r0=5 r3=1`.
+    pub fn register_writes(&self) -> String {
+        self.registers
+            .iter()
+            .map(|(register, value)| format!("r{}={}", register, value))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// One row per step, with stacks written bottom to top and separated by spaces.
+pub fn to_csv(trace: &[TraceEntry]) -> String {
+    let mut csv = String::from("step,pc,instruction,stack_before,stack_after,registers\n");
+    for (step, entry) in trace.iter().enumerate() {
+        csv.push_str(&format!(
+            "{},{},{},{},{},{}\n",
+            step,
+            entry.pc,
+            entry.instruction,
+            join(&entry.stack_before),
+            join(&entry.stack_after),
+            entry.register_writes()
+        ));
+    }
+    csv
+}
+
+pub fn to_jsonl(trace: &[TraceEntry]) -> String {
+    trace
+        .iter()
+        .enumerate()
+        .map(|(step, entry)| format!("{}\n", entry.to_json(step)))
+        .collect()
+}
+
+fn join(stack: &[f64]) -> String {
+    stack
+        .iter()
+        .map(|value| value.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl Stackalc {
+    /// Appends a step to the trace if one is being recorded. `stack` is from before the
+    /// instruction ran. An `stv` that had a value to store counts as a write even if the register
+    /// already held that value.
+    pub fn record_step(&mut self, pc: usize, instruction: Instruction, stack: Vec<f64>) {
+        let Some(trace) = &mut self.trace else {
+            return;
+        };
+
+        let registers = match instruction {
+            Instruction::STV(register) if register < self.memory.len() => stack
+                .last()
+                .map(|value| vec![(register, *value)])
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        };
+
+        trace.push(TraceEntry {
+            pc,
+            instruction,
+            stack_before: stack,
+            stack_after: self.stack.clone(),
+            registers,
+        });
+        self.trace_list_state.select(Some(trace.len() - 1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stackalc::CalcMode;
+
+    fn traced(source: &str) -> Vec<TraceEntry> {
+        let mut stackalc = Stackalc {
+            trace: Some(Vec::new()),
+            ..Stackalc::default()
+        };
+        stackalc.parse(CalcMode::RAW, source).unwrap();
+        stackalc.run_to_completion(100).unwrap();
+        stackalc.trace.unwrap()
+    }
+
+    #[test]
+    fn every_executed_step_is_recorded() {
+        let trace = traced("ldc:1 brtrue:3 ldc:9 ldc:5 stv:2");
+
+        let pcs: Vec<usize> = trace.iter().map(|entry| entry.pc).collect();
+        assert_eq!(pcs, [0, 1, 3, 4]);
+        assert_eq!(trace[1].stack_before, [1.0]);
+        assert!(trace[1].stack_after.is_empty());
+        assert_eq!(trace[3].registers, [(2, 5.0)]);
+    }
+
+    #[test]
+    fn storing_an_unchanged_value_is_still_a_write() {
+        let trace = traced("ldc:4 stv:0 ldc:4 stv:0");
+
+        assert_eq!(trace[3].registers, [(0, 4.0)]);
+    }
+
+    #[test]
+    fn csv_and_json_lines_have_one_record_per_step() {
+        let trace = traced("ldc:2 ldc:3 add stv:1");
+
+        assert_eq!(
+            to_csv(&trace),
+            "step,pc,instruction,stack_before,stack_after,registers\n\
+             0,0,ldc:2,,2,\n\
+             1,1,ldc:3,2,2 3,\n\
+             2,2,add,2 3,5,\n\
+             3,3,stv:1,5,,r1=5\n"
+        );
+        assert_eq!(
+            to_jsonl(&trace).lines().nth(3),
+            Some(
+                r#"{"step":3,"pc":3,"instruction":"stv:1","stack_before":[5],"stack_after":[],"registers":{"r1":5}}"#
+            )
+        );
+    }
+}
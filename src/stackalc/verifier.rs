@@ -0,0 +1,201 @@
+use crate::stackalc::instructions::Instruction;
+use std::fmt;
+use std::mem;
+
+/// Result of walking every branch path of a program, in the spirit of the CIL verifier.
+///
+/// `depths` holds the stack depth before each offset runs, with one extra slot for the end of
+/// the program. Offsets that no path reaches stay `None`.
+#[derive(Clone, Debug)]
+pub struct Verification {
+    pub depths: Vec<Option<usize>>,
+    pub issues: Vec<VerifyIssue>,
+}
+
+impl Verification {
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    pub fn issues_at(&self, offset: usize) -> impl Iterator<Item = &VerifyIssue> {
+        self.issues
+            .iter()
+            .filter(move |issue| issue.offset() == offset)
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.depths.iter().flatten().copied().max().unwrap_or(0)
+    }
+
+    fn report(&mut self, issue: VerifyIssue) {
+        let already_reported = self.issues.iter().any(|other| {
+            other.offset() == issue.offset()
+                && mem::discriminant(other) == mem::discriminant(&issue)
+        });
+
+        if !already_reported {
+            self.issues.push(issue);
+        }
+    }
+}
+
+pub fn verify(expr: &[Instruction]) -> Verification {
+    let mut verification = Verification {
+        depths: vec![None; expr.len() + 1],
+        issues: Vec::new(),
+    };
+    let mut worklist = vec![(0, 0)];
+
+    while let Some((offset, depth)) = worklist.pop() {
+        match verification.depths[offset] {
+            Some(expected) if expected != depth => {
+                verification.report(VerifyIssue::InconsistentDepth {
+                    offset,
+                    expected,
+                    found: depth,
+                });
+                continue;
+            }
+            Some(_) => continue,
+            None => verification.depths[offset] = Some(depth),
+        }
+
+        let Some(instruction) = expr.get(offset) else {
+            continue;
+        };
+
+        // The VM leaves the stack untouched when an instruction lacks operands, so a path that
+        // underflows carries on with the same depth and never takes its branch.
+        let (pops, pushes) = instruction.stack_effect();
+        if depth < pops {
+            verification.report(VerifyIssue::StackUnderflow {
+                offset,
+                depth,
+                required: pops,
+            });
+            worklist.push((offset + 1, depth));
+            continue;
+        }
+        let next_depth = depth - pops + pushes;
+
+        if let Some(target) = instruction.branch_target()
+            && target > expr.len()
+        {
+            verification.report(VerifyIssue::BranchOutOfRange { offset, target });
+        }
+        for successor in instruction.successors(offset, expr.len()) {
+            worklist.push((successor, next_depth));
+        }
+    }
+
+    verification.issues.sort_by_key(VerifyIssue::offset);
+    verification
+}
+
+#[derive(Clone, Debug)]
+pub enum VerifyIssue {
+    StackUnderflow {
+        offset: usize,
+        depth: usize,
+        required: usize,
+    },
+    InconsistentDepth {
+        offset: usize,
+        expected: usize,
+        found: usize,
+    },
+    BranchOutOfRange {
+        offset: usize,
+        target: usize,
+    },
+}
+
+impl VerifyIssue {
+    pub fn offset(&self) -> usize {
+        match self {
+            VerifyIssue::StackUnderflow { offset, .. }
+            | VerifyIssue::InconsistentDepth { offset, .. }
+            | VerifyIssue::BranchOutOfRange { offset, .. } => *offset,
+        }
+    }
+}
+
+impl fmt::Display for VerifyIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyIssue::StackUnderflow {
+                offset,
+                depth,
+                required,
+            } => write!(
+                f,
+                "Stack underflow at {:#06X}: needs {} values, has {}",
+                offset, required, depth
+            ),
+            VerifyIssue::InconsistentDepth {
+                offset,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Inconsistent stack depth at {:#06X}: {} on one path, {} on another",
+                offset, expected, found
+            ),
+            VerifyIssue::BranchOutOfRange { offset, target } => {
+                write!(
+                    f,
+                    "Branch at {:#06X} targets {:#06X}, out of range",
+                    offset, target
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stackalc::{CalcMode, Stackalc};
+
+    fn issues(source: &str) -> Vec<VerifyIssue> {
+        let mut stackalc = Stackalc::default();
+        stackalc.parse(CalcMode::RAW, source).unwrap();
+        verify(&stackalc.expr).issues
+    }
+
+    #[test]
+    fn reports_stack_underflow() {
+        assert!(matches!(
+            issues("ldc:1 add").as_slice(),
+            [VerifyIssue::StackUnderflow {
+                offset: 1,
+                depth: 1,
+                required: 2
+            }]
+        ));
+    }
+
+    #[test]
+    fn reports_inconsistent_depth() {
+        assert!(matches!(
+            issues("ldc:1 brtrue:3 ldc:2 nop").as_slice(),
+            [VerifyIssue::InconsistentDepth { offset: 3, .. }]
+        ));
+    }
+
+    #[test]
+    fn reports_branch_out_of_range() {
+        assert!(matches!(
+            issues("ldc:1 brfalse:9 ldc:2").as_slice(),
+            [VerifyIssue::BranchOutOfRange {
+                offset: 1,
+                target: 9
+            }]
+        ));
+    }
+
+    #[test]
+    fn accepts_balanced_branches() {
+        assert!(issues("ldc:1 brtrue:4 ldc:2 br:5 ldc:3 nop").is_empty());
+    }
+}
@@ -0,0 +1,345 @@
+use crate::stackalc::bytecode::{decode, encode};
+use crate::stackalc::cfg::Cfg;
+use crate::stackalc::export::c::{self, Translation};
+use crate::stackalc::export::{cil, wat, x86_64};
+use crate::stackalc::registers::MAX_REGISTERS;
+use crate::stackalc::session::{self, Session};
+use crate::stackalc::snapshot::Snapshot;
+use crate::stackalc::{LoadMode, Stackalc, program_file, trace};
+use crate::tui::file_picker::FilePicker;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+impl Stackalc {
+    pub fn execute_command(&mut self, file_picker: &mut Option<FilePicker>) {
+        let command = std::mem::take(&mut self.command_query);
+        let args: Vec<&str> = command.split_whitespace().collect();
+        // Paths are taken verbatim from the command line, so runs of spaces in them survive.
+        let path_of = |path: &[&str]| remainder(&command, args.len() - path.len());
+
+        let status = match args.as_slice() {
+            [] => return,
+            ["open"] => {
+                let dir = std::env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+                *file_picker = Some(FilePicker::new(dir));
+                return;
+            }
+            ["open", path @ ..] => self.open_program(Path::new(path_of(path))),
+            ["save", path @ ..] => self.save_program(path_of(path)),
+            ["export", "dot", path @ ..] => {
+                let dot = Cfg::build(&self.expr).to_dot(&self.expr);
+                write_file(path_of(path), "program.dot", "CFG", dot)
+            }
+            ["export", "c", path @ ..] => {
+                match c::export(&self.expr, self.memory.len(), Translation::Direct) {
+                    Ok(source) => write_file(path_of(path), "program.c", "C source", source),
+                    Err(err) => err.to_string(),
+                }
+            }
+            ["export", "c-regs", path @ ..] => {
+                match c::export(&self.expr, self.memory.len(), Translation::Promoted) {
+                    Ok(source) => write_file(path_of(path), "program.c", "C source", source),
+                    Err(err) => err.to_string(),
+                }
+            }
+            ["export", "il", path @ ..] => match cil::export(&self.expr, self.memory.len()) {
+                Ok(il) => write_file(path_of(path), "program.il", "CIL assembly", il),
+                Err(err) => err.to_string(),
+            },
+            ["export", "wat", path @ ..] => match wat::export(&self.expr, self.memory.len()) {
+                Ok(wat) => write_file(path_of(path), "program.wat", "WebAssembly module", wat),
+                Err(err) => err.to_string(),
+            },
+            ["export", "asm", path @ ..] => match x86_64::export(&self.expr, self.memory.len()) {
+                Ok(asm) => write_file(path_of(path), "program.s", "x86-64 assembly", asm),
+                Err(err) => err.to_string(),
+            },
+            ["export", "bin", path @ ..] => match encode(&self.expr, self.memory.len()) {
+                Ok(bytes) => write_file(path_of(path), "program.skbc", "bytecode", bytes),
+                Err(err) => err.to_string(),
+            },
+            ["import", "bin", path @ ..] => {
+                self.import_bytecode(&file_path(path_of(path), "program.skbc"))
+            }
+            ["trace", "export", "csv", path @ ..] => {
+                let csv = trace::to_csv(self.trace.as_deref().unwrap_or_default());
+                write_file(path_of(path), "trace.csv", "trace", csv)
+            }
+            ["trace", "export", "jsonl", path @ ..] => {
+                let jsonl = trace::to_jsonl(self.trace.as_deref().unwrap_or_default());
+                write_file(path_of(path), "trace.jsonl", "trace", jsonl)
+            }
+            ["trace", "clear"] => {
+                if let Some(trace) = &mut self.trace {
+                    trace.clear();
+                }
+                self.trace_list_state.select(None);
+                String::from("Trace cleared")
+            }
+            ["session", "save", path @ ..] => match session_path(path_of(path)) {
+                Some(path) => match self.write_session(&path) {
+                    Ok(()) => format!("Saved session to {}", path.display()),
+                    Err(err) => format!("Could not write {}: {}", path.display(), err),
+                },
+                None => String::from("No home directory to save the session in, give a path"),
+            },
+            ["session", "restore", path @ ..] => match session_path(path_of(path)) {
+                Some(path) => self.restore_session(&path),
+                None => String::from("No home directory to restore the session from, give a path"),
+            },
+            ["snapshot", "save", path @ ..] => {
+                let snapshot = Snapshot::capture(self, self.pc());
+                write_file(path_of(path), "snapshot.sks", "snapshot", snapshot.render())
+            }
+            ["snapshot", "load", path @ ..] => {
+                self.load_snapshot(&file_path(path_of(path), "snapshot.sks"))
+            }
+            ["seed"] => format!("Seed {}", self.seed),
+            ["seed", seed] => match seed.parse() {
+                Ok(seed) => {
+                    // Stepping back past this point would bring the old sequence back.
+                    self.set_seed(seed);
+                    self.history.clear();
+                    format!("Seed set to {}, random numbers start over", seed)
+                }
+                Err(_) => format!("Expected a seed between 0 and {}, got {}", u64::MAX, seed),
+            },
+            ["set", "cse", value] => match parse_switch(value) {
+                Some(enabled) => {
+                    self.cse = enabled;
+                    format!("Common-subexpression elimination {}", switch_name(enabled))
+                }
+                None => format!("Expected on or off, got {}", value),
+            },
+            ["set", "reorder", value] => match parse_switch(value) {
+                Some(enabled) => {
+                    self.reorder = enabled;
+                    format!("Stack-depth-minimising reordering {}", switch_name(enabled))
+                }
+                None => format!("Expected on or off, got {}", value),
+            },
+            _ => format!("Unknown command: {}", command.trim()),
+        };
+
+        self.status = Some(status);
+    }
+
+    /// Loads a program file as if its contents had been typed into the input bar and loaded in
+    /// its mode. Bytecode files are recognised by their `.skbc` extension.
+    pub fn open_program(&mut self, path: &Path) -> String {
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "skbc")
+        {
+            return self.import_bytecode(&path.display().to_string());
+        }
+
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) => return format!("Could not read {}: {}", path.display(), err),
+        };
+        let program = match program_file::parse(path, &contents) {
+            Ok(program) => program,
+            Err(err) => return format!("Could not open {}: {}", path.display(), err),
+        };
+
+        if let Some(mode) = program.mode {
+            self.calc_mode = mode;
+        }
+        self.calculator_query_char_idx = program.source.chars().count();
+        self.calculator_query = program.source;
+        if let Err(err) = self.load_input(LoadMode::Replace) {
+            return format!("Could not open {}: {}", path.display(), err);
+        }
+        self.program_path = Some(path.to_path_buf());
+
+        format!(
+            "Opened {} with {} instructions, VM reset",
+            path.display(),
+            self.expr.len()
+        )
+    }
+
+    pub fn write_session(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, Session::capture(self).render())
+    }
+
+    fn restore_session(&mut self, path: &Path) -> String {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) => return format!("Could not read {}: {}", path.display(), err),
+        };
+
+        match Session::parse(&contents).and_then(|session| session.restore(self)) {
+            Ok(()) => format!("Restored session from {}", path.display()),
+            Err(err) => format!("Could not restore {}: {}", path.display(), err),
+        }
+    }
+
+    /// Looks for a session saved on a previous exit and, if there is one, asks whether to
+    /// restore it before anything else happens.
+    pub fn offer_session(&mut self) {
+        let Some(path) = session::default_path() else {
+            return;
+        };
+        let Ok(contents) = fs::read_to_string(&path) else {
+            return;
+        };
+
+        match Session::parse(&contents) {
+            Ok(session) => {
+                self.status = Some(format!(
+                    "Restore the session from {} with {} instructions? [Y] Restore | [N] Start fresh",
+                    path.display(),
+                    session.snapshot.expr.len()
+                ));
+                self.pending_session = Some(session);
+            }
+            Err(err) => {
+                self.status = Some(format!("Could not read {}: {}", path.display(), err));
+            }
+        }
+    }
+
+    fn load_snapshot(&mut self, path: &str) -> String {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) => return format!("Could not read {}: {}", path, err),
+        };
+
+        match Snapshot::parse(&contents).and_then(|snapshot| snapshot.restore(self)) {
+            Ok(()) => format!(
+                "Loaded snapshot from {}, next instruction {:#06X}",
+                path,
+                self.pc()
+            ),
+            Err(err) => format!("Could not load {}: {}", path, err),
+        }
+    }
+
+    fn save_program(&mut self, path: &str) -> String {
+        let path = match (path, &self.program_path) {
+            ("", Some(program_path)) => program_path.clone(),
+            (path, _) => PathBuf::from(file_path(path, "program.sk")),
+        };
+
+        match fs::write(
+            &path,
+            program_file::render(&self.expr, self.memory.len(), &self.aliases),
+        ) {
+            Ok(()) => {
+                let status = format!(
+                    "Saved {} instructions to {}",
+                    self.expr.len(),
+                    path.display()
+                );
+                self.program_path = Some(path);
+                status
+            }
+            Err(err) => format!("Could not write {}: {}", path.display(), err),
+        }
+    }
+
+    fn import_bytecode(&mut self, path: &str) -> String {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(err) => return format!("Could not read {}: {}", path, err),
+        };
+
+        match decode(&bytes) {
+            Ok(bytecode) if bytecode.registers > MAX_REGISTERS => format!(
+                "{} needs {} registers, at most {} are supported",
+                path, bytecode.registers, MAX_REGISTERS
+            ),
+            Ok(bytecode) => {
+                let loaded = bytecode.expr.len();
+                self.replace_program(bytecode.expr);
+                self.set_register_count(bytecode.registers);
+                format!("Loaded {} instructions from {}, VM reset", loaded, path)
+            }
+            Err(err) => format!("Could not load {}: {}", path, err),
+        }
+    }
+}
+
+/// What follows the first `words` words of `command`, with the whitespace inside it kept.
+fn remainder(command: &str, words: usize) -> &str {
+    let mut rest = command.trim_start();
+    for _ in 0..words {
+        rest = rest
+            .trim_start_matches(|c: char| !c.is_whitespace())
+            .trim_start();
+    }
+    rest.trim_end()
+}
+
+fn file_path(path: &str, default: &str) -> String {
+    if path.is_empty() {
+        String::from(default)
+    } else {
+        String::from(path)
+    }
+}
+
+fn session_path(path: &str) -> Option<PathBuf> {
+    if path.is_empty() {
+        session::default_path()
+    } else {
+        Some(PathBuf::from(path))
+    }
+}
+
+fn write_file(path: &str, default: &str, what: &str, contents: impl AsRef<[u8]>) -> String {
+    let path = file_path(path, default);
+
+    match fs::write(&path, contents) {
+        Ok(()) => format!("Wrote {} to {}", what, path),
+        Err(err) => format!("Could not write {}: {}", path, err),
+    }
+}
+
+fn parse_switch(value: &str) -> Option<bool> {
+    match value {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    }
+}
+
+fn switch_name(enabled: bool) -> &'static str {
+    if enabled { "on" } else { "off" }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_keep_their_whitespace() {
+        assert_eq!(remainder("open a  b.sk", 1), "a  b.sk");
+        assert_eq!(
+            remainder(" export\tc-regs  out\tdir/p.c ", 2),
+            "out\tdir/p.c"
+        );
+        assert_eq!(remainder("save", 1), "");
+    }
+
+    #[test]
+    fn failed_open_keeps_the_program_and_its_path() {
+        let path = std::env::temp_dir().join(format!("stackalc-bad-{}.sk", std::process::id()));
+        fs::write(&path, "ldc:1 bogus\n").unwrap();
+
+        let mut stackalc = Stackalc {
+            calculator_query: String::from("1 + 2"),
+            ..Stackalc::default()
+        };
+        stackalc.load_input(LoadMode::Replace).unwrap();
+        let status = stackalc.open_program(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(status.starts_with("Could not open"), "{}", status);
+        assert_eq!(stackalc.expr.len(), 3);
+        assert!(stackalc.program_path.is_none());
+    }
+}
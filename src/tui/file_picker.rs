@@ -0,0 +1,143 @@
+use ratatui::prelude::*;
+use ratatui::widgets::{Block, Borders, Clear, HighlightSpacing, List, ListItem, ListState};
+use std::fs;
+use std::path::PathBuf;
+
+/// A popup listing one directory, with sub-directories first. Entering a directory lists it in
+/// place; entering a file hands its path back to the caller.
+#[derive(Clone)]
+pub struct FilePicker {
+    dir: PathBuf,
+    entries: Vec<Entry>,
+    state: ListState,
+}
+
+#[derive(Clone)]
+struct Entry {
+    name: String,
+    is_dir: bool,
+}
+
+impl FilePicker {
+    pub fn new(dir: PathBuf) -> FilePicker {
+        let mut picker = FilePicker {
+            dir,
+            entries: Vec::new(),
+            state: ListState::default(),
+        };
+        picker.read_dir();
+        picker
+    }
+
+    fn read_dir(&mut self) {
+        let mut entries: Vec<Entry> = fs::read_dir(&self.dir)
+            .map(|read_dir| {
+                read_dir
+                    .flatten()
+                    .map(|entry| Entry {
+                        name: entry.file_name().to_string_lossy().into_owned(),
+                        is_dir: entry.path().is_dir(),
+                    })
+                    .filter(|entry| !entry.name.starts_with('.'))
+                    .collect()
+            })
+            .unwrap_or_default();
+        entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+        if self.dir.parent().is_some() {
+            entries.insert(
+                0,
+                Entry {
+                    name: String::from(".."),
+                    is_dir: true,
+                },
+            );
+        }
+
+        self.entries = entries;
+        self.state.select(if self.entries.is_empty() {
+            None
+        } else {
+            Some(0)
+        });
+    }
+
+    pub fn next(&mut self) {
+        self.state.select_next();
+    }
+
+    pub fn previous(&mut self) {
+        self.state.select_previous();
+    }
+
+    pub fn parent(&mut self) {
+        if let Some(parent) = self.dir.parent() {
+            self.dir = parent.to_path_buf();
+            self.read_dir();
+        }
+    }
+
+    /// Opens the selected directory, or returns the selected file.
+    pub fn enter(&mut self) -> Option<PathBuf> {
+        let entry = self.entries.get(self.state.selected()?)?.clone();
+        if entry.name == ".." {
+            self.parent();
+            None
+        } else if entry.is_dir {
+            self.dir.push(&entry.name);
+            self.read_dir();
+            None
+        } else {
+            Some(self.dir.join(&entry.name))
+        }
+    }
+
+    pub fn render(&mut self, area: Rect, buf: &mut Buffer) {
+        let [_, area, _] = Layout::horizontal([
+            Constraint::Percentage(20),
+            Constraint::Percentage(60),
+            Constraint::Percentage(20),
+        ])
+        .areas(area);
+        let [_, area, _] = Layout::vertical([
+            Constraint::Percentage(20),
+            Constraint::Percentage(60),
+            Constraint::Percentage(20),
+        ])
+        .areas(area);
+
+        let block = Block::new()
+            .title(Line::raw(format!("Open {}", self.dir.display())))
+            .title_bottom(
+                Line::raw(" [ENTER] Open | [BACKSPACE] Up | [ESC] Cancel ").right_aligned(),
+            )
+            .borders(Borders::ALL)
+            .bg(Color::Rgb(24, 25, 38));
+
+        let dim = Style::default().fg(Color::Rgb(131, 139, 167));
+        let items: Vec<ListItem> = self
+            .entries
+            .iter()
+            .map(|entry| {
+                if entry.is_dir {
+                    ListItem::from(Line::styled(format!("{}/", entry.name), dim))
+                } else {
+                    ListItem::from(entry.name.as_str())
+                }
+            })
+            .collect();
+
+        let list = List::new(items)
+            .block(block)
+            .highlight_symbol(">")
+            .highlight_style(
+                Style::default()
+                    .add_modifier(Modifier::BOLD)
+                    .fg(Color::Rgb(166, 209, 137)),
+            )
+            .highlight_spacing(HighlightSpacing::Always);
+
+        Widget::render(Clear, area, buf);
+        StatefulWidget::render(list, area, buf, &mut self.state);
+    }
+}
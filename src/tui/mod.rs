@@ -1,36 +1,100 @@
-use crate::stackalc::{CalcMode, InputMode, Stackalc};
+use crate::stackalc::bytecode::{encode, instruction_ranges};
+use crate::stackalc::cfg::Cfg;
+use crate::stackalc::decompiler::decompile;
+use crate::stackalc::liveness::analyze;
+use crate::stackalc::optimizer::optimize;
+use crate::stackalc::parser::ParseError;
+use crate::stackalc::tac::lower;
+use crate::stackalc::verifier::verify;
+use crate::stackalc::{CalcMode, InputMode, LoadMode, Panel, Stackalc};
+use crate::tui::file_picker::FilePicker;
 use ratatui::DefaultTerminal;
 use ratatui::crossterm::event;
 use ratatui::crossterm::event::{Event, KeyEventKind};
 use ratatui::prelude::*;
 use ratatui::widgets::{
-    Block, Borders, Cell, HighlightSpacing, List, ListItem, Paragraph, Row, Table,
+    Block, Borders, Cell, HighlightSpacing, List, ListItem, ListState, Paragraph, Row, Table,
 };
 use std::io;
+use std::ops::Range;
 
+mod commands;
+pub mod file_picker;
 pub mod navigation;
 
 impl Stackalc {
-    pub fn load_input(&mut self) {
+    /// Loads the input bar and reports the outcome in the status line.
+    pub fn submit_input(&mut self, load_mode: LoadMode) {
+        self.status = Some(match self.load_input(load_mode) {
+            Ok(status) => status,
+            Err(err) => err.to_string(),
+        });
+    }
+
+    /// Compiles the input bar into the program, returning a summary of what was loaded. A bad
+    /// input leaves the current program and VM exactly as they were.
+    pub fn load_input(&mut self, load_mode: LoadMode) -> Result<String, ParseError> {
         let query = self.calculator_query.clone();
+        let previous = self.clone();
+
+        if load_mode == LoadMode::Replace {
+            self.clear();
+            self.instruction_list_state.select(None);
+        } else {
+            self.spans.fill(None);
+        }
+        let base = self.expr.len();
 
-        match self.calc_mode {
-            CalcMode::INFIX => self.parse_infix(&query),
-            CalcMode::POSTFIX => self.parse_postfix(&query),
-            CalcMode::RAW => self.parse_raw(&query),
+        let parsed = match load_mode {
+            LoadMode::Replace => self.parse(self.calc_mode, &query),
+            LoadMode::Append => self.parse_continuation(self.calc_mode, &query),
+        };
+        if let Err(err) = parsed {
+            *self = previous;
+            return Err(err);
+        }
+
+        for instruction in &mut self.expr[base..] {
+            instruction.relocate(base);
+        }
+
+        let loaded = self.expr.len() - base;
+        let mut status = match load_mode {
+            LoadMode::Replace => format!("Replaced program with {} instructions, VM reset", loaded),
+            LoadMode::Append => format!("Appended {} instructions at {:#06X}", loaded, base),
+        };
+        if let CalcMode::INFIX = self.calc_mode
+            && let Some((in_order, reordered)) = self.infix_peak_depths(&query)
+        {
+            status.push_str(&format!(
+                " | peak stack depth {} in operand order, {} reordered",
+                in_order, reordered
+            ));
         }
+
+        self.decompiled = match self.calc_mode {
+            CalcMode::RAW => Some(decompile(&self.expr)),
+            _ => None,
+        };
+        Ok(status)
     }
 
-    pub fn run(mut self, mut terminal: DefaultTerminal) -> io::Result<()> {
+    pub fn run(&mut self, mut terminal: DefaultTerminal) -> io::Result<()> {
+        // The picker is an overlay of the terminal UI only, so it lives here rather than in the VM.
+        let mut file_picker: Option<FilePicker> = None;
         while !self.exit {
             terminal.draw(|frame| {
-                frame.render_widget(&mut self, frame.area());
+                frame.render_widget(&mut *self, frame.area());
+                if let Some(file_picker) = &mut file_picker {
+                    let [_, _, main_area, _, _] = self.areas(frame.area());
+                    file_picker.render(main_area, frame.buffer_mut());
+                }
                 if let Some(cursor_position) = self.cursor_position {
                     frame.set_cursor_position(cursor_position);
                 }
             })?;
 
-            self.handle_events();
+            self.handle_events(&mut file_picker);
         }
 
         Ok(())
@@ -49,6 +113,19 @@ impl Stackalc {
                 ),
                 Span::styled(" to write", Style::default().fg(Color::Rgb(131, 139, 167))),
             ])
+        } else if let Some(span) = self.selected_span() {
+            let query = self.calculator_query.as_str();
+            Line::from(vec![
+                Span::styled(&query[..span.start], Style::default().fg(Color::White)),
+                Span::styled(
+                    &query[span.clone()],
+                    Style::default()
+                        .add_modifier(Modifier::BOLD)
+                        .fg(Color::Rgb(24, 25, 38))
+                        .bg(Color::Rgb(166, 209, 137)),
+                ),
+                Span::styled(&query[span.end..], Style::default().fg(Color::White)),
+            ])
         } else {
             Line::from(Span::styled(
                 self.calculator_query.as_str(),
@@ -83,9 +160,71 @@ impl Stackalc {
         search_bar.render(area, buf);
     }
 
+    fn selected_span(&self) -> Option<Range<usize>> {
+        let instruction_idx = self.instruction_list_state.selected()?;
+        let span = self.spans.get(instruction_idx)?.clone()?;
+
+        self.calculator_query.get(span.clone()).map(|_| span)
+    }
+
+    fn render_decompiled(&self, area: Rect, buf: &mut Buffer) {
+        let block = Block::default()
+            .borders(Borders::ALL)
+            .title("Decompiled")
+            .bg(Color::Rgb(24, 25, 38));
+
+        let label_style = Style::default()
+            .add_modifier(Modifier::BOLD)
+            .fg(Color::Rgb(131, 139, 167));
+
+        let lines = match &self.decompiled {
+            Some(Ok(decompiled)) => vec![
+                Line::from(vec![
+                    Span::styled(" infix   ", label_style),
+                    Span::raw(decompiled.infix()),
+                ]),
+                Line::from(vec![
+                    Span::styled(" postfix ", label_style),
+                    Span::raw(decompiled.postfix()),
+                ]),
+            ],
+            Some(Err(err)) => vec![Line::from(Span::styled(
+                format!(" {}", err),
+                Style::default().fg(Color::Rgb(237, 135, 150)),
+            ))],
+            None => Vec::new(),
+        };
+
+        Paragraph::new(lines).block(block).render(area, buf);
+    }
+
     fn render_instructions(&mut self, area: Rect, buf: &mut Buffer) {
+        let verification = verify(&self.expr);
+        let liveness = analyze(&self.expr);
+        let issue_style = Style::default().fg(Color::Rgb(237, 135, 150));
+        let warning_style = Style::default().fg(Color::Rgb(238, 212, 159));
+
+        let mut summary = Vec::new();
+        if !self.expr.is_empty() {
+            summary.push(if verification.is_valid() {
+                Span::styled(" verified ", Style::default().fg(Color::Rgb(166, 209, 137)))
+            } else {
+                Span::styled(
+                    format!(" {} issues ", verification.issues.len()),
+                    issue_style,
+                )
+            });
+        }
+        if !liveness.warnings.is_empty() {
+            summary.push(Span::styled(
+                format!(" {} warnings ", liveness.warnings.len()),
+                warning_style,
+            ));
+        }
+
         let block = Block::new()
             .title(Line::raw("Instructions"))
+            .title_bottom(Line::from(summary).right_aligned())
             .borders(Borders::ALL)
             .bg(Color::Rgb(24, 25, 38));
 
@@ -95,8 +234,28 @@ impl Stackalc {
             .enumerate()
             .map(|(i, instruction)| {
                 let offset = format!("{:#06X}", i);
-                let line = format!("{}  {}", offset, instruction.to_string());
-                ListItem::from(line)
+                let depth = verification.depths[i]
+                    .map(|depth| format!("{:>3}", depth))
+                    .unwrap_or_else(|| String::from("  -"));
+
+                let mut spans = vec![
+                    Span::raw(format!("{}  ", offset)),
+                    Span::styled(depth, Style::default().fg(Color::Rgb(131, 139, 167))),
+                    Span::raw(format!("  {}", instruction)),
+                ];
+                for issue in verification.issues_at(i) {
+                    spans.push(Span::styled(format!("  ; {}", issue), issue_style));
+                }
+                for warning in liveness.warnings_at(i) {
+                    spans.push(Span::styled(format!("  ; {}", warning), warning_style));
+                }
+
+                let line = Line::from(spans);
+                if liveness.reachable[i] {
+                    ListItem::from(line)
+                } else {
+                    ListItem::from(line.style(Style::default().fg(Color::Rgb(91, 96, 120))))
+                }
             })
             .collect();
 
@@ -113,6 +272,242 @@ impl Stackalc {
         StatefulWidget::render(list, area, buf, &mut self.instruction_list_state);
     }
 
+    fn render_panel(&mut self, panel: Panel, area: Rect, buf: &mut Buffer) {
+        match panel {
+            Panel::Cfg => self.render_cfg(area, buf),
+            Panel::Optimised => self.render_optimised(area, buf),
+            Panel::Tac => self.render_tac(area, buf),
+            Panel::Bytecode => self.render_bytecode(area, buf),
+            Panel::Trace => self.render_trace(area, buf),
+        }
+    }
+
+    fn render_optimised(&self, area: Rect, buf: &mut Buffer) {
+        let optimised = optimize(&self.expr);
+
+        let block = Block::new()
+            .title(Line::raw(format!(
+                "Optimised ({} -> {} instructions)",
+                self.expr.len(),
+                optimised.len()
+            )))
+            .borders(Borders::ALL)
+            .bg(Color::Rgb(24, 25, 38));
+
+        let items: Vec<ListItem> = optimised
+            .iter()
+            .enumerate()
+            .map(|(i, instruction)| ListItem::from(format!("{:#06X}  {}", i, instruction)))
+            .collect();
+
+        Widget::render(List::new(items).block(block), area, buf);
+    }
+
+    fn render_tac(&self, area: Rect, buf: &mut Buffer) {
+        let block = Block::new()
+            .title(Line::raw("Three-address code"))
+            .borders(Borders::ALL)
+            .bg(Color::Rgb(24, 25, 38));
+
+        let items: Vec<ListItem> = lower(&self.expr)
+            .into_iter()
+            .enumerate()
+            .map(|(i, statements)| {
+                let offset = Span::raw(format!("{:#06X}  ", i));
+                if statements.is_empty() {
+                    let dim = Style::default().fg(Color::Rgb(131, 139, 167));
+                    ListItem::from(Line::from(vec![offset, Span::styled("-", dim)]))
+                } else {
+                    ListItem::from(Line::from(vec![offset, Span::raw(statements.join("; "))]))
+                }
+            })
+            .collect();
+
+        let list = List::new(items)
+            .block(block)
+            .highlight_symbol(">")
+            .highlight_style(
+                Style::default()
+                    .add_modifier(Modifier::BOLD)
+                    .fg(Color::Rgb(166, 209, 137)),
+            )
+            .highlight_spacing(HighlightSpacing::Always);
+
+        // Follows the Instructions list, which has already been rendered and scrolled this frame.
+        let mut state = ListState::default()
+            .with_offset(self.instruction_list_state.offset())
+            .with_selected(self.instruction_list_state.selected());
+        StatefulWidget::render(list, area, buf, &mut state);
+    }
+
+    fn render_bytecode(&self, area: Rect, buf: &mut Buffer) {
+        const ROW: usize = 8;
+        let bytes = match encode(&self.expr, self.memory.len()) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                let block = Block::new()
+                    .title(Line::raw("Bytecode"))
+                    .borders(Borders::ALL)
+                    .bg(Color::Rgb(24, 25, 38));
+                Paragraph::new(err.to_string())
+                    .block(block)
+                    .render(area, buf);
+                return;
+            }
+        };
+        let selected = self
+            .instruction_list_state
+            .selected()
+            .and_then(|offset| instruction_ranges(&self.expr).get(offset).cloned());
+
+        let block = Block::new()
+            .title(Line::raw(format!("Bytecode ({} bytes)", bytes.len())))
+            .borders(Borders::ALL)
+            .bg(Color::Rgb(24, 25, 38));
+
+        let dim = Style::default().fg(Color::Rgb(131, 139, 167));
+        let highlight = Style::default()
+            .add_modifier(Modifier::BOLD)
+            .fg(Color::Rgb(166, 209, 137));
+        let mut scroll = 0;
+
+        let lines: Vec<Line> = bytes
+            .chunks(ROW)
+            .enumerate()
+            .map(|(row, chunk)| {
+                let start = row * ROW;
+                let mut spans = vec![Span::styled(format!("{:04X} ", start), dim)];
+                for (i, byte) in chunk.iter().enumerate() {
+                    let style = match &selected {
+                        Some(range) if range.contains(&(start + i)) => {
+                            if range.start == start + i {
+                                scroll = row;
+                            }
+                            highlight
+                        }
+                        _ => Style::default(),
+                    };
+                    spans.push(Span::styled(format!(" {:02X}", byte), style));
+                }
+                let ascii: String = chunk
+                    .iter()
+                    .map(|&byte| {
+                        if byte.is_ascii_graphic() {
+                            byte as char
+                        } else {
+                            '.'
+                        }
+                    })
+                    .collect();
+                spans.push(Span::raw("   ".repeat(ROW - chunk.len())));
+                spans.push(Span::styled(format!("  {}", ascii), dim));
+                Line::from(spans)
+            })
+            .collect();
+
+        Paragraph::new(lines)
+            .block(block)
+            .scroll((scroll.saturating_sub(2) as u16, 0))
+            .render(area, buf);
+    }
+
+    fn render_trace(&mut self, area: Rect, buf: &mut Buffer) {
+        let trace = self.trace.as_deref().unwrap_or_default();
+
+        let block = Block::new()
+            .title(Line::raw(format!("Trace ({} steps)", trace.len())))
+            .borders(Borders::ALL)
+            .bg(Color::Rgb(24, 25, 38));
+
+        let dim = Style::default().fg(Color::Rgb(131, 139, 167));
+        let items: Vec<ListItem> = trace
+            .iter()
+            .enumerate()
+            .map(|(step, entry)| {
+                let mut line = vec![
+                    Span::styled(format!("{:>4} ", step), dim),
+                    Span::raw(format!("{:#06X}  {:<10}", entry.pc, entry.instruction)),
+                    Span::styled(
+                        format!("{:?} -> {:?}", entry.stack_before, entry.stack_after),
+                        dim,
+                    ),
+                ];
+                if !entry.registers.is_empty() {
+                    line.push(Span::raw(format!("  {}", entry.register_writes())));
+                }
+                ListItem::from(Line::from(line))
+            })
+            .collect();
+
+        let list = List::new(items)
+            .block(block)
+            .highlight_symbol(">")
+            .highlight_style(
+                Style::default()
+                    .add_modifier(Modifier::BOLD)
+                    .fg(Color::Rgb(166, 209, 137)),
+            )
+            .highlight_spacing(HighlightSpacing::Always);
+
+        StatefulWidget::render(list, area, buf, &mut self.trace_list_state);
+    }
+
+    fn render_cfg(&self, area: Rect, buf: &mut Buffer) {
+        let cfg = Cfg::build(&self.expr);
+        let selected_block = self
+            .instruction_list_state
+            .selected()
+            .and_then(|offset| cfg.block_of(offset));
+
+        let block = Block::new()
+            .title(Line::raw(format!("CFG ({} blocks)", cfg.blocks.len())))
+            .borders(Borders::ALL)
+            .bg(Color::Rgb(24, 25, 38));
+
+        let dim = Style::default().fg(Color::Rgb(131, 139, 167));
+        let mut lines = Vec::new();
+        let mut scroll = 0;
+
+        for (id, basic_block) in cfg.blocks.iter().enumerate() {
+            let header_style = if selected_block == Some(id) {
+                scroll = lines.len();
+                Style::default()
+                    .add_modifier(Modifier::BOLD)
+                    .fg(Color::Rgb(166, 209, 137))
+            } else {
+                Style::default().add_modifier(Modifier::BOLD)
+            };
+
+            lines.push(Line::styled(
+                format!(
+                    "B{}  {:#06X}..{:#06X}",
+                    id,
+                    basic_block.start,
+                    basic_block.end - 1
+                ),
+                header_style,
+            ));
+            for offset in basic_block.start..basic_block.end {
+                lines.push(Line::styled(
+                    format!("    {:#06X}  {}", offset, self.expr[offset]),
+                    dim,
+                ));
+            }
+
+            let successors: Vec<String> = basic_block
+                .successors
+                .iter()
+                .map(|edge| edge.to_string())
+                .collect();
+            lines.push(Line::raw(format!("    -> {}", successors.join(", "))));
+        }
+
+        Paragraph::new(lines)
+            .block(block)
+            .scroll((scroll as u16, 0))
+            .render(area, buf);
+    }
+
     fn render_stack(&mut self, area: Rect, buf: &mut Buffer) {
         let block = Block::new()
             .title(Line::raw("Stack"))
@@ -142,7 +537,7 @@ impl Stackalc {
             .iter()
             .enumerate()
             .map(|(i, value)| {
-                let reg_name = format!("r{}", i);
+                let reg_name = self.register_label(i);
                 let value_str = value
                     .as_ref()
                     .map(|v| v.to_string())
@@ -151,7 +546,7 @@ impl Stackalc {
             })
             .collect();
 
-        let table = Table::new(rows, [Constraint::Length(12), Constraint::Min(12)])
+        let table = Table::new(rows, [Constraint::Length(16), Constraint::Min(12)])
             .block(block)
             .header(
                 Row::new(vec!["Register", "Value"])
@@ -163,10 +558,35 @@ impl Stackalc {
         Widget::render(table, area, buf);
     }
 
+    fn render_status(&mut self, area: Rect, buf: &mut Buffer) {
+        if self.input_mode == InputMode::Command {
+            Paragraph::new(format!(":{}", self.command_query))
+                .style(Style::default().fg(Color::White).bg(Color::Rgb(24, 25, 38)))
+                .render(area, buf);
+            self.cursor_position = Some(Position::new(
+                area.x + self.command_query.chars().count() as u16 + 1,
+                area.y,
+            ));
+        } else if let Some(status) = &self.status {
+            Paragraph::new(format!(" {}", status))
+                .style(
+                    Style::default()
+                        .fg(Color::Rgb(131, 139, 167))
+                        .bg(Color::Rgb(24, 25, 38)),
+                )
+                .render(area, buf);
+        }
+    }
+
     fn render_footer(&mut self, area: Rect, buf: &mut Buffer) {
+        let seed = format!(" seed {} ", self.seed);
         let layout = Layout::default()
             .direction(Direction::Horizontal)
-            .constraints([Constraint::Length(9), Constraint::Min(0)])
+            .constraints([
+                Constraint::Length(9),
+                Constraint::Min(0),
+                Constraint::Length(seed.len() as u16),
+            ])
             .split(area);
 
         let calc_mode = {
@@ -191,7 +611,7 @@ impl Stackalc {
             );
 
         let actions = String::from(
-            "  [TAB] Input | [R] Raw | [I] Infix | [P] Postfix | [C] Clear | [Down] Execute next | [Q] Quit",
+            "  [TAB] Input | [ENTER] Load | [A] Append | [R] Raw | [I] Infix | [P] Postfix | [C] Clear | [G] CFG | [O] Optimised | [T] TAC | [H] Hex | [X] Trace | [:] Command | [Down] Execute next | [Up] Step back | [Q] Quit",
         );
 
         let actions_paragraph = Paragraph::new(actions)
@@ -203,46 +623,125 @@ impl Stackalc {
                     .add_modifier(Modifier::REVERSED),
             );
 
+        let seed_paragraph = Paragraph::new(seed).style(
+            Style::default()
+                .bg(Color::White)
+                .fg(Color::Rgb(131, 139, 167))
+                .add_modifier(Modifier::REVERSED),
+        );
+
         calc_mode_paragraph.render(layout[0], buf);
         actions_paragraph.render(layout[1], buf);
+        seed_paragraph.render(layout[2], buf);
     }
 
-    fn handle_events(&mut self) {
-        if let Ok(event) = event::read() {
-            if let Event::Key(event) = event {
-                if event.kind == KeyEventKind::Press {
-                    self.handle_key_event(event)
-                }
-            }
+    fn handle_events(&mut self, file_picker: &mut Option<FilePicker>) {
+        if let Ok(Event::Key(event)) = event::read()
+            && event.kind == KeyEventKind::Press
+        {
+            self.handle_key_event(event, file_picker)
         }
     }
-}
 
-impl Widget for &mut Stackalc {
-    fn render(self, area: Rect, buf: &mut Buffer) {
-        let [input_bar_area, main_area, footer_area] = Layout::default()
+    /// Input bar, decompiled line, main area, status line and footer, top to bottom.
+    fn areas(&self, area: Rect) -> [Rect; 5] {
+        let decompiled_height = if self.decompiled.is_some() { 4 } else { 0 };
+
+        let status_height = if self.status.is_some() || self.input_mode == InputMode::Command {
+            1
+        } else {
+            0
+        };
+
+        Layout::default()
             .direction(Direction::Vertical)
             .constraints([
                 Constraint::Length(3),
+                Constraint::Length(decompiled_height),
                 Constraint::Min(0),
+                Constraint::Length(status_height),
                 Constraint::Length(1),
             ])
-            .areas(area);
+            .areas(area)
+    }
+}
+
+impl Widget for &mut Stackalc {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let [
+            input_bar_area,
+            decompiled_area,
+            main_area,
+            status_area,
+            footer_area,
+        ] = self.areas(area);
 
         self.render_input_bar(input_bar_area, buf);
+        if self.decompiled.is_some() {
+            self.render_decompiled(decompiled_area, buf);
+        }
+        self.render_status(status_area, buf);
         self.render_footer(footer_area, buf);
 
-        let [instructions_area, stack_area, memory_area] = Layout::default()
-            .direction(Direction::Horizontal)
-            .constraints([
-                Constraint::Percentage(43),
-                Constraint::Percentage(43),
-                Constraint::Percentage(14),
-            ])
-            .areas(main_area);
+        if let Some(panel) = self.panel {
+            let [instructions_area, panel_area, stack_area, memory_area] = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([
+                    Constraint::Percentage(30),
+                    Constraint::Percentage(30),
+                    Constraint::Percentage(26),
+                    Constraint::Percentage(14),
+                ])
+                .areas(main_area);
+
+            self.render_instructions(instructions_area, buf);
+            self.render_panel(panel, panel_area, buf);
+            self.render_stack(stack_area, buf);
+            self.render_registers(memory_area, buf);
+        } else {
+            let [instructions_area, stack_area, memory_area] = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([
+                    Constraint::Percentage(43),
+                    Constraint::Percentage(43),
+                    Constraint::Percentage(14),
+                ])
+                .areas(main_area);
+
+            self.render_instructions(instructions_area, buf);
+            self.render_stack(stack_area, buf);
+            self.render_registers(memory_area, buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stackalc::instructions::Instruction;
 
-        self.render_instructions(instructions_area, buf);
-        self.render_stack(stack_area, buf);
-        self.render_registers(memory_area, buf);
+    fn load(stackalc: &mut Stackalc, query: &str, load_mode: LoadMode) {
+        stackalc.calculator_query = query.to_string();
+        stackalc.load_input(load_mode).unwrap();
+    }
+
+    #[test]
+    fn appending_relocates_branches_and_keeps_the_vm() {
+        let mut stackalc = Stackalc {
+            calc_mode: CalcMode::RAW,
+            ..Stackalc::default()
+        };
+        load(&mut stackalc, "ldc:5", LoadMode::Replace);
+        stackalc.next();
+
+        load(&mut stackalc, "ldc:0 brfalse:3 ldc:9", LoadMode::Append);
+        assert_eq!(stackalc.expr[2], Instruction::BRFALSE(4));
+        assert_eq!(stackalc.stack, [5.0]);
+        assert_eq!(stackalc.pc(), 1);
+
+        load(&mut stackalc, "ldc:7", LoadMode::Replace);
+        assert_eq!(stackalc.expr, [Instruction::LDC(7.0)]);
+        assert!(stackalc.stack.is_empty());
+        assert_eq!(stackalc.pc(), 0);
     }
 }
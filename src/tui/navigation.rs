@@ -1,8 +1,45 @@
-use crate::stackalc::{CalcMode, InputMode, Stackalc};
-use ratatui::crossterm::event::{KeyCode, KeyEvent};
+use crate::stackalc::{CalcMode, Checkpoint, InputMode, LoadMode, Panel, Stackalc};
+use crate::tui::file_picker::FilePicker;
+use ratatui::crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
 
 impl Stackalc {
-    pub fn handle_key_event(&mut self, key: KeyEvent) {
+    pub fn handle_key_event(&mut self, key: KeyEvent, file_picker: &mut Option<FilePicker>) {
+        if let Some(picker) = file_picker {
+            match key.code {
+                KeyCode::Esc => *file_picker = None,
+                KeyCode::Up => picker.previous(),
+                KeyCode::Down => picker.next(),
+                KeyCode::Backspace => picker.parent(),
+                KeyCode::Enter => {
+                    if let Some(path) = picker.enter() {
+                        *file_picker = None;
+                        self.status = Some(self.open_program(&path));
+                    }
+                }
+                _ => {}
+            }
+            return;
+        }
+
+        if self.pending_session.is_some() {
+            match key.code {
+                KeyCode::Char('y' | 'Y') | KeyCode::Enter => {
+                    let session = self.pending_session.take().unwrap();
+                    self.status = Some(match session.restore(self) {
+                        Ok(()) => String::from("Restored the previous session"),
+                        Err(err) => format!("Could not restore the previous session: {}", err),
+                    });
+                }
+                KeyCode::Char('n' | 'N') | KeyCode::Esc => {
+                    self.pending_session = None;
+                    self.status = None;
+                }
+                KeyCode::Char('q') => self.exit = true,
+                _ => {}
+            }
+            return;
+        }
+
         match self.input_mode {
             InputMode::Normal => match key.code {
                 KeyCode::Tab => self.input_mode = InputMode::Insert,
@@ -11,28 +48,99 @@ impl Stackalc {
                 KeyCode::Char('p') => self.calc_mode = CalcMode::POSTFIX,
                 KeyCode::Char('r') => self.calc_mode = CalcMode::RAW,
                 KeyCode::Char('c') => self.clear(),
+                KeyCode::Enter => self.submit_input(LoadMode::Replace),
+                KeyCode::Char('a') => self.submit_input(LoadMode::Append),
+                KeyCode::Char('g') => self.toggle_panel(Panel::Cfg),
+                KeyCode::Char('o') => self.toggle_panel(Panel::Optimised),
+                KeyCode::Char('t') => self.toggle_panel(Panel::Tac),
+                KeyCode::Char('h') => self.toggle_panel(Panel::Bytecode),
+                KeyCode::Char('x') => self.toggle_panel(Panel::Trace),
+                KeyCode::PageUp if self.panel == Some(Panel::Trace) => {
+                    self.trace_list_state.scroll_up_by(10)
+                }
+                KeyCode::PageDown if self.panel == Some(Panel::Trace) => {
+                    self.trace_list_state.scroll_down_by(10)
+                }
+                KeyCode::Char(':') => {
+                    self.command_query.clear();
+                    self.input_mode = InputMode::Command;
+                }
                 KeyCode::Down => {
                     self.next();
                 }
+                KeyCode::Up => {
+                    self.previous();
+                }
                 _ => {}
             },
             InputMode::Insert => match key.code {
                 KeyCode::Esc => self.input_mode = InputMode::Normal,
-                KeyCode::Enter => self.load_input(),
+                KeyCode::Enter if key.modifiers.contains(KeyModifiers::ALT) => {
+                    self.submit_input(LoadMode::Append)
+                }
+                KeyCode::Enter => self.submit_input(LoadMode::Replace),
                 KeyCode::Char(to_insert) => self.enter_char(to_insert),
                 KeyCode::Backspace => self.delete_char(),
                 KeyCode::Left => self.move_cursor_left(),
                 KeyCode::Right => self.move_cursor_right(),
                 _ => {}
             },
+            InputMode::Command => match key.code {
+                KeyCode::Esc => self.input_mode = InputMode::Normal,
+                KeyCode::Enter => {
+                    self.input_mode = InputMode::Normal;
+                    self.execute_command(file_picker);
+                }
+                KeyCode::Char(to_insert) => self.command_query.push(to_insert),
+                KeyCode::Backspace => {
+                    self.command_query.pop();
+                }
+                _ => {}
+            },
+        }
+    }
+
+    fn toggle_panel(&mut self, panel: Panel) {
+        if self.panel == Some(panel) {
+            self.panel = None;
+        } else {
+            self.panel = Some(panel);
         }
     }
 
     pub fn next(&mut self) {
+        // Past the last instruction nothing runs, so there is nothing to undo either.
+        if self.pc() >= self.expr.len() {
+            return;
+        }
+        self.history.push(Checkpoint {
+            selected: self.instruction_list_state.selected(),
+            stack: self.stack.clone(),
+            memory: self.memory.clone(),
+            rng: self.rng,
+            trace_len: self.trace.as_ref().map_or(0, Vec::len),
+        });
         self.instruction_list_state.select_next();
         self.execute_selected();
     }
 
+    /// Undoes the last step. The random generator goes back with it, so stepping forward again
+    /// pushes the same values.
+    pub fn previous(&mut self) {
+        let Some(checkpoint) = self.history.pop() else {
+            return;
+        };
+
+        self.instruction_list_state.select(checkpoint.selected);
+        self.stack = checkpoint.stack;
+        self.memory = checkpoint.memory;
+        self.rng = checkpoint.rng;
+        if let Some(trace) = &mut self.trace {
+            trace.truncate(checkpoint.trace_len);
+            self.trace_list_state.select(trace.len().checked_sub(1));
+        }
+    }
+
     fn move_cursor_left(&mut self) {
         let cursor_moved_left = self.calculator_query_char_idx.saturating_sub(1);
         self.calculator_query_char_idx = self.clamp_cursor(cursor_moved_left);
@@ -46,6 +154,7 @@ impl Stackalc {
     fn enter_char(&mut self, new_char: char) {
         let index = self.byte_index();
         self.calculator_query.insert(index, new_char);
+        self.spans.fill(None);
         self.move_cursor_right();
     }
 
@@ -70,6 +179,7 @@ impl Stackalc {
             let after_char_to_delete = self.calculator_query.chars().skip(current_index);
 
             self.calculator_query = before_char_to_delete.chain(after_char_to_delete).collect();
+            self.spans.fill(None);
             self.move_cursor_left();
         }
     }